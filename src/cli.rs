@@ -0,0 +1,1671 @@
+//! The command line interface, converting files with the options given.
+
+use std::{
+    collections::HashMap,
+    fmt, fs,
+    io::{self, Write},
+    net::TcpListener,
+    path::{Path, PathBuf},
+    process::{ExitCode, Stdio},
+};
+
+use anyhow::{bail, Context};
+use clap::{Parser, Subcommand, ValueEnum};
+use fantoccini::{Client, ClientBuilder};
+use geojson::{Feature, FeatureCollection};
+use indicatif::{ProgressBar, ProgressDrawTarget, ProgressStyle};
+use tokio::{
+    net::TcpStream,
+    process::{Child, Command},
+    time::{sleep, sleep_until, Duration, Instant},
+};
+use tracing::{info, warn};
+use tracing_subscriber::EnvFilter;
+use url::Url;
+
+use crate::{
+    csv::{
+        clustered_features, read_csv, records_to_geojson, CsvColumns, Encoding,
+        Record, CLUSTER_PRECISION,
+    },
+    driver::{
+        get_coords_for_url, needs_browser, next_step, Browser, ClientPool,
+        CoordCache, LookupOptions, LookupReport, RateLimiter, WaitStrategy,
+    },
+    extract::{clean_url, default_extractors, Provider},
+    files::{format_extension, is_stdio},
+    geojson::{
+        bounding_box, fill_geojson_coords, lookup_url, read_geojson,
+        NULL_ISLAND_EPSILON,
+    },
+    kml::read_kml,
+    output::{
+        merged_features, parse_property_map, read_output_features,
+        read_resolved_coords, write_output, OutputFormat, OutputOptions,
+        PropertyMap,
+    },
+    parse_bounds, Bounds, LatLng, Progress, Result,
+};
+
+// How long to wait for a spawned WebDriver server to start listening.
+const DRIVER_STARTUP_TIMEOUT: Duration = Duration::from_secs(10);
+
+// How long to go between saving the output while looking up places, at
+// least, and at least how many times longer than saving it took. Rewriting a
+// large output after every place would make a run take time quadratic in its
+// size.
+const CHECKPOINT_INTERVAL: Duration = Duration::from_secs(5);
+const CHECKPOINT_COST_FACTOR: u32 = 20;
+
+// A place whose url has no coordinates, so `doctor` has to load its page.
+const DOCTOR_URL: &str = "https://www.google.com/maps/place/Eiffel+Tower";
+
+/// Read GeoJSON and CSV files exported from Google Maps and converts them to
+/// GeoJSON files with coordinates for each place.
+///
+/// First run a WebDriver server in another terminal, such as geckodriver:
+///
+/// `cargo install geckodriver && geckodriver`
+#[derive(Debug, Parser)]
+#[command(version, about, subcommand_negates_reqs = true)]
+struct Cli {
+    #[command(subcommand)]
+    command: Option<CliCommand>,
+
+    /// Input filename, or a directory of input files to convert each of
+    ///
+    /// If the extension is "csv" or "tsv", it is interpreted as CSV, if it is
+    /// "kml" or "kmz", it is interpreted as KML, otherwise it is interpreted as
+    /// GeoJSON, unless --input-format is given. Comment lines in CSV files are
+    /// skipped with --csv-comment-char. If "-", GeoJSON is read from stdin.
+    #[arg(short, long, value_name = "FILE", required = true)]
+    input: Option<PathBuf>,
+
+    /// Input file format, overriding the one implied by the input's extension,
+    /// e.g. for stdin or a CSV ending in ".txt". Every file in an input
+    /// directory is read in this format
+    #[arg(long, value_enum, value_name = "FORMAT")]
+    input_format: Option<InputFormat>,
+
+    /// (CSV only) The column holding each place's title
+    #[arg(long, value_name = "COLUMN", default_value = "Title")]
+    csv_title_column: String,
+
+    /// (CSV only) The column holding each place's note
+    #[arg(long, value_name = "COLUMN", default_value = "Note")]
+    csv_note_column: String,
+
+    /// (CSV only) The column holding each place's Google Maps url
+    #[arg(long, value_name = "COLUMN", default_value = "URL")]
+    csv_url_column: String,
+
+    /// (CSV only) The column holding each place's comment
+    #[arg(long, value_name = "COLUMN", default_value = "Comment")]
+    csv_comment_column: String,
+
+    /// (CSV only) The column holding the latitude of places that already have
+    /// coordinates, which aren't looked up
+    #[arg(long, value_name = "COLUMN", default_value = "Latitude")]
+    csv_latitude_column: String,
+
+    /// (CSV only) The column holding the longitude of places that already
+    /// have coordinates, which aren't looked up
+    #[arg(long, value_name = "COLUMN", default_value = "Longitude")]
+    csv_longitude_column: String,
+
+    /// (CSV only) Keep every other column as a property of the place. CSV
+    /// output always keeps them
+    #[arg(long)]
+    csv_extra_columns: bool,
+
+    /// (CSV only) The character separating fields, or "\t" for tabs [default:
+    /// tab for .tsv files, "," otherwise]
+    #[arg(long, value_name = "CHAR", value_parser = parse_csv_char)]
+    delimiter: Option<u8>,
+
+    /// (CSV only) Skip lines starting with this character, such as the comment
+    /// line at the top of Google's exports
+    #[arg(long, value_name = "CHAR", value_parser = parse_csv_char)]
+    csv_comment_char: Option<u8>,
+
+    /// (CSV only) Character encoding of the input
+    #[arg(long, value_enum, default_value_t = Encoding::Utf8)]
+    encoding: Encoding,
+
+    /// Output filename, GeoJSON formatted unless --output-format is given, or
+    /// "-" for stdout
+    #[arg(short, long, value_name = "FILE", required = true)]
+    output: Option<PathBuf>,
+
+    /// What to do if the output file already exists. --resume continues from
+    /// an existing output regardless
+    #[arg(
+        long,
+        value_enum,
+        value_name = "MODE",
+        default_value_t = IfExists::Fail
+    )]
+    if_exists: IfExists,
+
+    /// Output file format. Defaults to GPX if the output extension is "gpx",
+    /// newline-delimited GeoJSON if it's "ndjson" or "jsonl", CSV if it's
+    /// "csv", otherwise GeoJSON.
+    #[arg(long, value_enum, value_name = "FORMAT")]
+    output_format: Option<OutputFormat>,
+
+    /// Only output the places that got coordinates. Otherwise the rest are
+    /// kept too, with CSV rows at null island (0, 0) to mark them as missing
+    /// coordinates
+    #[arg(long)]
+    only_changed_places: bool,
+
+    /// Don't set the bounding box of the output's points on the GeoJSON
+    #[arg(long)]
+    no_bbox: bool,
+
+    /// Round the output's coordinates to N decimal places
+    #[arg(long, value_name = "N")]
+    coord_precision: Option<u32>,
+
+    /// Indent the output GeoJSON for reading and diffing, instead of writing
+    /// it compactly
+    #[arg(long)]
+    pretty: bool,
+
+    /// Rename the output's properties, e.g. "title=label,url=source_url". The
+    /// properties are title, url, note and comment
+    #[arg(long, value_name = "MAP", value_parser = parse_property_map)]
+    property_map: Option<PropertyMap>,
+
+    /// Set a property on every place, e.g. "source=takeout-2024" to tag where
+    /// the places came from. Can be given more than once
+    #[arg(long, value_name = "KEY=VALUE", value_parser = parse_set_property)]
+    set_property: Vec<(String, String)>,
+
+    /// Remove tracking parameters, like "entry=" and "g_ep=", from the places'
+    /// urls, before looking them up and in the output
+    #[arg(long)]
+    clean_urls: bool,
+
+    /// (CSV only) Combine places with the same coordinates into one feature,
+    /// with a count and the names of the places
+    #[arg(long)]
+    cluster_identical: bool,
+
+    /// The port to connect to the WebDriver server on localhost. Defaults to
+    /// 4444.
+    #[arg(
+        short,
+        long,
+        global = true,
+        value_name = "PORT",
+        env = "GMAPS_COORDS_WEBDRIVER_PORT"
+    )]
+    port: Option<u16>,
+
+    /// The url of the WebDriver server, used as is, if it isn't on localhost.
+    /// E.g. "http://grid.internal:4444/wd/hub". Overrides --port. Repeat it,
+    /// or separate urls with commas, to spread lookups across several servers
+    #[arg(
+        long,
+        global = true,
+        value_name = "URL",
+        value_delimiter = ',',
+        env = "GMAPS_COORDS_WEBDRIVER_URL"
+    )]
+    webdriver_url: Vec<Url>,
+
+    /// Start geckodriver or chromedriver from the PATH, on --port or a free
+    /// port, and stop it when done
+    #[arg(long, global = true, conflicts_with = "webdriver_url")]
+    spawn_driver: bool,
+
+    /// Show the browser as coordinates are looked up
+    #[arg(long, global = true)]
+    noheadless: bool,
+
+    /// Leave the browser open for this long when done, to look at the page,
+    /// with --noheadless
+    #[arg(long, value_name = "SECS", conflicts_with = "keep_open")]
+    keep_open_secs: Option<u64>,
+
+    /// Leave the browser open until Enter is pressed when done, to look at
+    /// the page, with --noheadless
+    #[arg(long)]
+    keep_open: bool,
+
+    /// Resize the browser window, e.g. "1920x1080", to get the page's desktop
+    /// layout
+    #[arg(long, value_name = "WIDTHxHEIGHT", value_parser = parse_window_size)]
+    window_size: Option<(u32, u32)>,
+
+    /// Click "Accept all" if Google shows a cookie consent page
+    #[arg(long, global = true, requires = "noheadless")]
+    accept_consent: bool,
+
+    /// Use the first result for URLs of searches that list several places,
+    /// instead of failing them
+    #[arg(long)]
+    first_result: bool,
+
+    /// Fail places found outside the region, which is likely the wrong place,
+    /// given as "MIN_LNG,MIN_LAT,MAX_LNG,MAX_LAT"
+    #[arg(
+        long,
+        value_name = "BOUNDS",
+        value_parser = parse_bounds,
+        allow_hyphen_values = true
+    )]
+    bounds: Option<Bounds>,
+
+    /// The browser the WebDriver server drives
+    #[arg(long, global = true, value_enum, default_value_t = Browser::Firefox)]
+    browser: Browser,
+
+    /// The map provider of the URLs. Google is the only provider whose pages
+    /// are loaded when the URL has no coordinates
+    #[arg(long, value_enum, default_value_t = Provider::Auto)]
+    provider: Provider,
+
+    /// Override the browser's user agent, e.g. if Google serves a different
+    /// page to headless browsers
+    #[arg(long, global = true, value_name = "AGENT")]
+    user_agent: Option<String>,
+
+    /// Send the browser's traffic through a proxy, e.g. "http://proxy:3128"
+    /// or "socks5://proxy:1080". Only the browser uses it, not the connection
+    /// to the WebDriver server
+    #[arg(long, global = true, value_name = "URL", value_parser = parse_proxy)]
+    proxy: Option<Url>,
+
+    /// How long to wait for each place's coordinates before giving up
+    #[arg(long, global = true, value_name = "SECS", default_value_t = 10)]
+    timeout_secs: u64,
+
+    /// How long to wait for the coordinates of places identified only by a
+    /// place id (ftid=0x...:0x... or !1s0x...:0x...), which take longer to load
+    #[arg(long, value_name = "SECS", default_value_t = 30)]
+    place_id_timeout_secs: u64,
+
+    /// Retry places that time out once, with double the timeout
+    #[arg(long)]
+    retry_timeout: bool,
+
+    /// How to tell when the page has found the coordinates
+    #[arg(long, value_enum, default_value_t = WaitStrategy::Poll)]
+    wait_strategy: WaitStrategy,
+
+    /// Wait for the page's coordinates to be the same in two polls in a row,
+    /// rather than taking them while the map may still be moving
+    #[arg(long)]
+    wait_settled: bool,
+
+    /// How often to check whether the page has found the coordinates
+    #[arg(long, value_name = "MILLIS", default_value_t = 100)]
+    poll_interval_ms: u64,
+
+    /// Log more detail about each lookup. The RUST_LOG environment variable
+    /// takes precedence if set.
+    #[arg(short, long)]
+    verbose: bool,
+
+    /// Only log warnings and errors, without the progress bar or summary
+    #[arg(short, long, conflicts_with = "verbose")]
+    quiet: bool,
+
+    /// The minimum time between going to one place's url and the next, to
+    /// avoid being rate limited by Google Maps
+    #[arg(long, value_name = "MILLIS", default_value_t = 0)]
+    min_delay_ms: u64,
+
+    /// How many times to retry a lookup after a WebDriver error
+    #[arg(long, value_name = "N", default_value_t = 3)]
+    max_retries: u32,
+
+    /// Stop looking up places after the first one that fails
+    #[arg(long)]
+    fail_fast: bool,
+
+    /// Only look up the first N places that need it, e.g. to try out a setup
+    /// before a long run
+    #[arg(long, value_name = "N")]
+    limit: Option<usize>,
+
+    /// Skip the first N places that need looking up, before applying --limit.
+    /// Places already found with --resume don't count towards N
+    #[arg(long, value_name = "N", default_value_t = 0)]
+    skip: usize,
+
+    /// (CSV only) Read the name of places with an empty title from their page
+    #[arg(long)]
+    fetch_names: bool,
+
+    /// Look up every place with a Google Maps URL, replacing the coordinates
+    /// it has, not just those missing coordinates. The --cache isn't read,
+    /// but is updated with the coordinates found
+    #[arg(long)]
+    force_refetch: bool,
+
+    /// Add a "resolved_at" property with the time, in RFC 3339 format, to each
+    /// place whose coordinates were looked up in this run
+    #[arg(long)]
+    stamp_time: bool,
+
+    /// Add a "resolved_via" property to each place looked up in this run, with
+    /// how its coordinates were found: "cache", from the url ("data-param",
+    /// "q-param", "apple-param" or "osm-param"), or from the page in the
+    /// browser ("redirect" or "first-result")
+    #[arg(long)]
+    annotate_method: bool,
+
+    /// How close to null island (0, 0), in degrees, a point can be and still be
+    /// treated as missing coordinate data
+    #[arg(long, value_name = "DEGREES", default_value_t = NULL_ISLAND_EPSILON)]
+    null_island_epsilon: f64,
+
+    /// Only treat points exactly at null island as missing coordinate data,
+    /// for places genuinely near it
+    #[arg(long, conflicts_with = "null_island_epsilon")]
+    exact_null_island: bool,
+
+    /// Only count the places that need their coordinates looked up, without
+    /// connecting to WebDriver or writing the output
+    #[arg(long)]
+    dry_run: bool,
+
+    /// Only count the places' urls on each host, e.g. to spot short links,
+    /// without connecting to WebDriver or writing the output
+    #[arg(long, conflicts_with = "dry_run")]
+    report_hosts: bool,
+
+    /// How many browser sessions to look up coordinates with at once, and at
+    /// least one for each --webdriver-url
+    #[arg(long, value_name = "N", default_value_t = 1)]
+    concurrency: usize,
+
+    /// JSON file of previously found coordinates, updated as places are found
+    #[arg(long, value_name = "FILE")]
+    cache: Option<PathBuf>,
+
+    /// Keep the places already found in an existing GeoJSON, NDJSON or CSV
+    /// output file, and only look up the rest
+    #[arg(long)]
+    resume: bool,
+
+    /// Print a report of how long the lookups took at the end
+    #[arg(long)]
+    report: bool,
+
+    /// Stop looking up places after SECS seconds, saving those found so far,
+    /// and exit with code 124
+    #[arg(long, value_name = "SECS")]
+    max_runtime_secs: Option<u64>,
+
+    /// Write the report of how long the lookups took to a JSON file
+    #[arg(long, value_name = "FILE")]
+    report_file: Option<PathBuf>,
+
+    /// Write the places that couldn't be looked up to FILE, with the error
+    /// for each, in the format implied by its extension. It can be used as
+    /// the input of a later run to retry them
+    #[arg(long, value_name = "FILE")]
+    failures_file: Option<PathBuf>,
+}
+
+/// A command to run instead of converting files.
+#[derive(Debug, Subcommand)]
+enum CliCommand {
+    /// Check that the WebDriver server and browser can look up a place's
+    /// coordinates, printing how to fix each step that fails
+    Doctor,
+}
+
+/// A file format to read places from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum InputFormat {
+    /// A CSV of places, such as a saved list
+    Csv,
+    /// A GeoJSON FeatureCollection, such as the starred places
+    Geojson,
+    /// A KML or KMZ file, such as a My Maps export
+    Kml,
+}
+
+impl InputFormat {
+    /// The format implied by the path's extension, defaulting to GeoJSON.
+    fn from_path(path: &Path) -> Self {
+        match format_extension(path) {
+            Some("csv" | "tsv") => InputFormat::Csv,
+            Some("kml" | "kmz") => InputFormat::Kml,
+            _ => InputFormat::Geojson,
+        }
+    }
+}
+
+/// What to do when the output file already exists.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum IfExists {
+    /// Replace the existing output
+    Overwrite,
+    /// Stop without looking anything up
+    Fail,
+    /// Add the places to the existing output, replacing those with the same
+    /// url
+    Merge,
+}
+
+/// Run the command-line interface, failing if any place couldn't be looked up
+pub async fn run() -> anyhow::Result<ExitCode> {
+    let cli = Cli::parse();
+    let deadline = cli
+        .max_runtime_secs
+        .map(|secs| Instant::now() + Duration::from_secs(secs));
+
+    let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| {
+        EnvFilter::new(match (cli.verbose, cli.quiet) {
+            (true, _) => "gmaps_coords=debug",
+            (_, true) => "gmaps_coords=warn",
+            _ => "gmaps_coords=info",
+        })
+    });
+    // hidden until lookups start, and never shown if stderr isn't a terminal
+    let bar = ProgressBar::hidden().with_style(
+        ProgressStyle::with_template(
+            "[{elapsed_precise}] {bar:40} {pos}/{len} ({msg}) ETA {eta}",
+        )
+        .unwrap(),
+    );
+    let log_bar = bar.clone();
+    tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_writer(move || LogWriter(log_bar.clone()))
+        .init();
+
+    if let Some(CliCommand::Doctor) = cli.command {
+        return doctor(&cli).await;
+    }
+
+    let null_island_epsilon = match cli.exact_null_island {
+        true => 0.0,
+        false => cli.null_island_epsilon,
+    };
+    let csv_columns = CsvColumns {
+        title: cli.csv_title_column.clone(),
+        note: cli.csv_note_column.clone(),
+        url: cli.csv_url_column.clone(),
+        comment: cli.csv_comment_column.clone(),
+        latitude: cli.csv_latitude_column.clone(),
+        longitude: cli.csv_longitude_column.clone(),
+        null_island_epsilon,
+        extra: cli.csv_extra_columns,
+        delimiter: cli.delimiter,
+        comment_char: cli.csv_comment_char,
+        encoding: cli.encoding,
+    };
+    // an error in one file of a batch shouldn't stop the others
+    let batch = cli.input.as_ref().is_some_and(|input| input.is_dir());
+    let mut errors = 0;
+    let mut conversions = vec![];
+    for (input_path, output) in conversion_paths(&cli)? {
+        match prepare_conversion(
+            &cli,
+            input_path,
+            output,
+            &csv_columns,
+            null_island_epsilon,
+        ) {
+            Ok(conversion) => conversions.push(conversion),
+            Err(e) if batch => {
+                warn!("{e:#}. Skipping.");
+                errors += 1;
+            }
+            Err(e) => return Err(e),
+        }
+    }
+    let mut lookup = LookupOptions {
+        timeout: Duration::from_secs(cli.timeout_secs),
+        place_id_timeout: Duration::from_secs(cli.place_id_timeout_secs),
+        retry_timeout: cli.retry_timeout,
+        poll_interval: Duration::from_millis(cli.poll_interval_ms),
+        wait_strategy: cli.wait_strategy,
+        wait_settled: cli.wait_settled,
+        cache: cli
+            .cache
+            .clone()
+            .map(|path| CoordCache::load(path).context("Failed to read cache"))
+            .transpose()?,
+        resolved: HashMap::new(),
+        null_island_epsilon,
+        max_retries: cli.max_retries,
+        rate_limiter: RateLimiter::new(Duration::from_millis(cli.min_delay_ms)),
+        provider: cli.provider,
+        extractors: default_extractors(),
+        accept_consent: cli.accept_consent,
+        first_result: cli.first_result,
+        bounds: cli.bounds,
+        fail_fast: cli.fail_fast,
+        fetch_names: cli.fetch_names,
+        report: (cli.report || cli.report_file.is_some())
+            .then(LookupReport::new),
+        limit: cli.limit,
+        skip: cli.skip,
+        force_refetch: cli.force_refetch,
+        stamp_time: cli.stamp_time,
+        annotate_method: cli.annotate_method,
+    };
+
+    if cli.report_hosts {
+        let mut hosts: HashMap<String, usize> = HashMap::new();
+        for conversion in &conversions {
+            for url in conversion.input.urls() {
+                let host = match Url::parse(url) {
+                    Ok(url) => url.host_str().unwrap_or("(no host)").to_owned(),
+                    Err(_) => "(invalid url)".to_owned(),
+                };
+                *hosts.entry(host).or_default() += 1;
+            }
+        }
+        let mut hosts = hosts.into_iter().collect::<Vec<_>>();
+        hosts.sort_by(|(a, m), (b, n)| n.cmp(m).then_with(|| a.cmp(b)));
+        for (host, count) in hosts {
+            println!("{count:>8} {host}");
+        }
+        return Ok(match errors {
+            0 => ExitCode::SUCCESS,
+            _ => ExitCode::FAILURE,
+        });
+    }
+
+    if cli.dry_run {
+        for conversion in &mut conversions {
+            lookup.resolved = std::mem::take(&mut conversion.resolved);
+            let (total, needed) = conversion.input.count_lookups(&lookup);
+            if batch {
+                print!("{}: ", conversion.input_path.display());
+            }
+            println!(
+                "{needed} of {total} places need their coordinates looked up"
+            );
+        }
+        return Ok(match errors {
+            0 => ExitCode::SUCCESS,
+            _ => ExitCode::FAILURE,
+        });
+    }
+
+    // check that we can write to the output files, without overwriting,
+    // before spending lots of time fetching coordinates
+    for conversion in &conversions {
+        let exists = fs::metadata(&conversion.output)
+            .map(|m| m.len() > 0)
+            .unwrap_or(false);
+        if exists && cli.if_exists == IfExists::Fail && !cli.resume {
+            bail!(
+                "Output file {} already exists. Pass --if-exists overwrite or \
+                merge to write to it anyway",
+                conversion.output.display()
+            );
+        }
+        if !is_stdio(&conversion.output) {
+            fs::OpenOptions::new()
+                .create(true)
+                .truncate(false)
+                .write(true)
+                .open(&conversion.output)
+                .with_context(|| {
+                    format!("Cannot write to {}", conversion.output.display())
+                })?;
+        }
+    }
+
+    // places that can be found from the cache or their url alone don't need
+    // the browser, so it's only started once a file has others left. The
+    // driver is killed when dropped, including on errors.
+    let mut pool = ClientPool::empty();
+    let mut driver = None;
+
+    if !cli.quiet {
+        bar.set_draw_target(ProgressDrawTarget::stderr());
+    }
+    let (mut succeeded, mut failed, mut total) = (0, 0, 0);
+    let mut stopped = None;
+    let mut failures = vec![];
+    // returned once the sessions are closed, so they aren't left running
+    let mut fatal = None;
+    for mut conversion in conversions {
+        if deadline.is_some_and(|d| Instant::now() >= d) {
+            warn!("Reached the maximum runtime. Stopping.");
+            stopped = Some(Stop::OutOfTime);
+            break;
+        }
+        lookup.resolved = std::mem::take(&mut conversion.resolved);
+        let input_path = conversion.input_path.clone();
+        let browser_lookups = conversion.input.browser_lookups(&lookup);
+        if pool.is_empty() && browser_lookups > 0 {
+            (pool, driver) = connect_pool(&cli).await.with_context(|| {
+                format!(
+                    "The browser is needed to look up {browser_lookups} \
+                    place(s) in {}",
+                    input_path.display()
+                )
+            })?;
+        }
+        if batch {
+            info!(
+                "Converting {} to {}",
+                input_path.display(),
+                conversion.output.display()
+            );
+        }
+        match convert(&cli, &pool, conversion, &lookup, &bar, deadline).await {
+            Ok(tally) => {
+                succeeded += tally.succeeded;
+                failed += tally.failed;
+                total += tally.total;
+                stopped = tally.stopped;
+                failures.extend(tally.failures);
+            }
+            Err(e) if batch => {
+                warn!(
+                    "Failed to convert {} with error {e:#}. {}",
+                    input_path.display(),
+                    next_step(&lookup)
+                );
+                errors += 1;
+            }
+            Err(e) => {
+                fatal = Some(e);
+                break;
+            }
+        }
+        if stopped.is_some() || (failed + errors > 0 && lookup.fail_fast) {
+            break;
+        }
+    }
+
+    bar.finish_and_clear();
+    if pool.is_empty() {
+        info!("No places needed the browser, so it wasn't started");
+    } else if cli.noheadless {
+        keep_open(&cli).await;
+    }
+    let closed = pool
+        .close()
+        .await
+        .context("Failed to close WebDriver clients");
+    if let Some(mut driver) = driver {
+        if let Err(e) = driver.kill().await {
+            warn!("Failed to stop {} with error {e}.", cli.browser.driver());
+        }
+    }
+    if let Some(e) = fatal {
+        if let Err(close_error) = closed {
+            warn!("{close_error:#}");
+        }
+        return Err(e);
+    }
+    closed?;
+
+    if !cli.quiet {
+        eprintln!("resolved {succeeded} / failed {failed} / total {total}");
+    }
+    if let Some(path) = &cli.failures_file {
+        let format = OutputFormat::from_path(path);
+        let options = OutputOptions {
+            format,
+            null_island_epsilon,
+            precision: None,
+            pretty: cli.pretty,
+            properties: output_properties(&cli, format, &csv_columns),
+            columns: vec![],
+        };
+        let features = FeatureCollection {
+            features: failures,
+            bbox: None,
+            foreign_members: None,
+        };
+        write_output(path, &features, &options)
+            .context("Failed to write the failures file")?;
+    }
+    if let Some(report) = &lookup.report {
+        let summary = report.summary();
+        if cli.report {
+            eprint!("{summary}");
+        }
+        if let Some(path) = &cli.report_file {
+            fs::write(path, serde_json::to_string_pretty(&summary)?)
+                .context("Failed to write the report file")?;
+        }
+    }
+    Ok(match (stopped, failed + errors) {
+        // the conventional exit code after SIGINT
+        (Some(Stop::Interrupted), _) => ExitCode::from(130),
+        // as from timeout(1)
+        (Some(Stop::OutOfTime), _) => ExitCode::from(124),
+        (None, 0) => ExitCode::SUCCESS,
+        (None, _) => ExitCode::FAILURE,
+    })
+}
+
+/// Why the lookups were stopped early.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Stop {
+    /// By Ctrl-C.
+    Interrupted,
+    /// By reaching the maximum runtime.
+    OutOfTime,
+}
+
+/// Wait before the browser is closed for as long as asked to, or until Ctrl-C.
+async fn keep_open(cli: &Cli) {
+    let closed = async {
+        if cli.keep_open {
+            eprintln!("Press Enter to close the browser");
+            // read on its own thread, which doesn't hold up exiting on Ctrl-C
+            let (tx, rx) = tokio::sync::oneshot::channel();
+            std::thread::spawn(move || {
+                let _ = io::stdin().read_line(&mut String::new());
+                let _ = tx.send(());
+            });
+            let _ = rx.await;
+        } else if let Some(secs) = cli.keep_open_secs {
+            info!("Closing the browser in {secs} seconds");
+            sleep(Duration::from_secs(secs)).await;
+        }
+    };
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => {}
+        _ = closed => {}
+    }
+}
+
+/// Wait for Ctrl-C, or the deadline to pass if there is one.
+async fn stop_signal(deadline: Option<Instant>) -> Stop {
+    let out_of_time = async {
+        match deadline {
+            Some(deadline) => sleep_until(deadline).await,
+            None => std::future::pending().await,
+        }
+    };
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => Stop::Interrupted,
+        _ = out_of_time => Stop::OutOfTime,
+    }
+}
+
+/// How many places of an input file were looked up.
+#[derive(Debug, Clone)]
+struct Tally {
+    succeeded: usize,
+    failed: usize,
+    /// How many places were to be looked up.
+    total: usize,
+    /// Why the lookups were stopped early, if they were.
+    stopped: Option<Stop>,
+    /// The features that coordinates couldn't be found for.
+    failures: Vec<Feature>,
+}
+
+/// An input file read to be converted, and where to write the result.
+struct Conversion {
+    input_path: PathBuf,
+    input: Input,
+    output: PathBuf,
+    format: OutputFormat,
+    /// Coordinates already found in the output, when resuming.
+    resolved: HashMap<String, LatLng>,
+    /// The features of the existing output, when merging into it.
+    existing: Option<Vec<Feature>>,
+    /// The output names of the places' properties.
+    properties: PropertyMap,
+    /// The columns of a CSV input, in order, which CSV output starts with.
+    columns: Vec<String>,
+}
+
+/// The output names of the places' properties in the format. CSV output uses
+/// the names of the CSV input's columns unless a property map is given.
+fn output_properties(
+    cli: &Cli,
+    format: OutputFormat,
+    csv_columns: &CsvColumns,
+) -> PropertyMap {
+    match (&cli.property_map, format) {
+        (Some(map), _) => map.clone(),
+        (None, OutputFormat::Csv) => PropertyMap {
+            name: csv_columns.title.clone(),
+            url: csv_columns.url.clone(),
+            note: csv_columns.note.clone(),
+            comment: csv_columns.comment.clone(),
+        },
+        (None, _) => PropertyMap::default(),
+    }
+}
+
+/// The input and output path of every file to convert. If the input is a
+/// directory, every file in it that can be read is converted, and written to
+/// the output directory with the same name and the output format's extension.
+fn conversion_paths(cli: &Cli) -> anyhow::Result<Vec<(PathBuf, PathBuf)>> {
+    // both are required without a subcommand
+    let (Some(input), Some(output)) = (&cli.input, &cli.output) else {
+        bail!("--input and --output are required");
+    };
+    if !input.is_dir() {
+        return Ok(vec![(input.clone(), output.clone())]);
+    }
+    if is_stdio(output) {
+        bail!("Cannot write a directory of inputs to stdout");
+    }
+    fs::create_dir_all(output).with_context(|| {
+        format!("Cannot create output directory {}", output.display())
+    })?;
+    let extension = match cli.output_format.unwrap_or(OutputFormat::Geojson) {
+        OutputFormat::Geojson => "geojson",
+        OutputFormat::Gpx => "gpx",
+        OutputFormat::Ndjson => "ndjson",
+        OutputFormat::Csv => "csv",
+    };
+    let mut inputs = vec![];
+    for entry in fs::read_dir(input)? {
+        let path = entry?.path();
+        let readable = cli.input_format.is_some()
+            || matches!(
+                format_extension(&path),
+                Some("csv" | "tsv" | "json" | "geojson" | "kml" | "kmz")
+            );
+        if path.is_file() && readable {
+            inputs.push(path);
+        }
+    }
+    inputs.sort();
+    let mut paths: Vec<(PathBuf, PathBuf)> = vec![];
+    for input in inputs {
+        let file_output = output
+            .join(input.file_stem().unwrap_or_default())
+            .with_extension(extension);
+        if let Some((other, _)) = paths.iter().find(|(_, o)| *o == file_output)
+        {
+            bail!(
+                "{} and {} would both be written to {}",
+                other.display(),
+                input.display(),
+                file_output.display()
+            );
+        }
+        paths.push((input, file_output));
+    }
+    Ok(paths)
+}
+
+/// Read an input file, and the places already found in its output if
+/// resuming.
+fn prepare_conversion(
+    cli: &Cli,
+    input_path: PathBuf,
+    output: PathBuf,
+    csv_columns: &CsvColumns,
+    null_island_epsilon: f64,
+) -> anyhow::Result<Conversion> {
+    let input_format = cli
+        .input_format
+        .unwrap_or_else(|| InputFormat::from_path(&input_path));
+    let format = cli
+        .output_format
+        .unwrap_or_else(|| OutputFormat::from_path(&output));
+    // CSV output keeps every column of a CSV input
+    let csv_columns = &CsvColumns {
+        extra: csv_columns.extra || format == OutputFormat::Csv,
+        ..csv_columns.clone()
+    };
+    let (mut input, columns) =
+        read_input(&input_path, input_format, csv_columns).with_context(
+            || format!("Failed to read {}", input_path.display()),
+        )?;
+    input.set_properties(&cli.set_property);
+    if cli.clean_urls {
+        input.clean_urls();
+    }
+    let properties = output_properties(cli, format, csv_columns);
+    let resolved = match cli.resume {
+        true if is_stdio(&output) => {
+            bail!("Cannot resume when writing the output to stdout")
+        }
+        true => read_resolved_coords(
+            &output,
+            format,
+            &properties,
+            null_island_epsilon,
+        )
+        .with_context(|| {
+            format!(
+                "Failed to read output file {} to resume from",
+                output.display()
+            )
+        })?,
+        false => HashMap::new(),
+    };
+    let existing = match cli.if_exists {
+        IfExists::Merge if is_stdio(&output) => None,
+        IfExists::Merge if format == OutputFormat::Gpx => {
+            bail!("Cannot merge into GPX output")
+        }
+        IfExists::Merge => {
+            let features = read_output_features(&output, format, &properties)
+                .with_context(|| {
+                format!(
+                    "Failed to read output file {} to merge into",
+                    output.display()
+                )
+            })?;
+            Some(features)
+        }
+        _ => None,
+    };
+    Ok(Conversion {
+        input_path,
+        input,
+        output,
+        format,
+        resolved,
+        existing,
+        properties,
+        columns,
+    })
+}
+
+/// Look up the places of an input file and write them to its output. On
+/// Ctrl-C, or once the deadline passes, the places found so far are written,
+/// and the lookups stop.
+async fn convert(
+    cli: &Cli,
+    pool: &ClientPool,
+    conversion: Conversion,
+    lookup: &LookupOptions,
+    bar: &ProgressBar,
+    deadline: Option<Instant>,
+) -> anyhow::Result<Tally> {
+    let Conversion {
+        input,
+        output,
+        format: output_format,
+        existing,
+        properties,
+        columns,
+        ..
+    } = conversion;
+    let null_island_epsilon = lookup.null_island_epsilon;
+    let options = OutputOptions {
+        format: output_format,
+        null_island_epsilon,
+        precision: cli.coord_precision,
+        pretty: cli.pretty,
+        properties,
+        columns,
+    };
+
+    // save progress as places are found, so a crash doesn't lose it all
+    bar.reset();
+    let (places, needed) = input.count_lookups(lookup);
+    if needed == 0 {
+        // so an input that's already complete doesn't look like a bug
+        info!("None of the {places} places need their coordinates looked up");
+    }
+    bar.set_length(needed as u64);
+    let (mut succeeded, mut failed, mut total) = (0, 0, 0);
+    // features already streamed to stdout, by line, so they aren't repeated
+    let streaming = is_stdio(&output) && output_format == OutputFormat::Ndjson;
+    let mut streamed: HashMap<String, usize> = HashMap::new();
+    // streamed features can't be clustered after they're written, so clusters
+    // would repeat them
+    let cluster = cli.cluster_identical
+        && matches!(input, Input::Records(_))
+        && !streaming;
+    let cluster_precision = cli.coord_precision.unwrap_or(CLUSTER_PRECISION);
+    // the output at the last checkpoint, in case it needs writing to stdout
+    // on Ctrl-C
+    let mut latest = None;
+    let mut failures = vec![];
+    let (mut last_checkpoint, mut checkpoint_took) =
+        (Instant::now(), Duration::ZERO);
+    let checkpoint = |progress: Progress| {
+        (succeeded, failed, total) =
+            (progress.succeeded, progress.failed, progress.total);
+        bar.set_length(progress.total as u64);
+        bar.set_position((progress.succeeded + progress.failed) as u64);
+        bar.set_message(format!(
+            "{} found, {} failed",
+            progress.succeeded, progress.failed
+        ));
+        failures.extend_from_slice(progress.failures);
+        if let (true, Some(feature)) = (streaming, progress.found) {
+            println!("{}", options.output_feature(feature));
+            *streamed.entry(feature.to_string()).or_default() += 1;
+        }
+        let interval =
+            CHECKPOINT_INTERVAL.max(checkpoint_took * CHECKPOINT_COST_FACTOR);
+        if last_checkpoint.elapsed() < interval {
+            return;
+        }
+        let start = Instant::now();
+        let clustered;
+        let features = match cluster {
+            true => {
+                clustered = clustered_features(
+                    progress.features,
+                    cluster_precision,
+                    null_island_epsilon,
+                );
+                &clustered
+            }
+            false => progress.features,
+        };
+        // stdout can't be rewritten, so only write it once at the end
+        if is_stdio(&output) {
+            if !streaming {
+                latest = Some(features.clone());
+            }
+        } else {
+            let merged;
+            let features = match &existing {
+                Some(existing) => {
+                    merged = merged_features(existing, features);
+                    &merged
+                }
+                None => features,
+            };
+            if let Err(e) = write_output(&output, features, &options) {
+                warn!(
+                    "Failed to write progress to output file with error {e}. \
+                    Continuing."
+                );
+            }
+        }
+        (last_checkpoint, checkpoint_took) = (Instant::now(), start.elapsed());
+    };
+    let lookups = async {
+        match input {
+            Input::Geojson(feature_collection) => {
+                fill_geojson_coords(
+                    pool,
+                    feature_collection,
+                    cli.only_changed_places,
+                    lookup,
+                    checkpoint,
+                )
+                .await
+            }
+            Input::Records(records) => {
+                records_to_geojson(
+                    pool,
+                    records,
+                    cli.only_changed_places,
+                    lookup,
+                    checkpoint,
+                )
+                .await
+            }
+        }
+    };
+    let mut features = tokio::select! {
+        features = lookups => features,
+        stop = stop_signal(deadline) => {
+            let reason = match stop {
+                Stop::Interrupted => "Interrupted",
+                Stop::OutOfTime => "Reached the maximum runtime",
+            };
+            warn!("{reason}. Saving the places found so far.");
+            // output files are already saved at each checkpoint, so only the
+            // places found since the last one are lost
+            if let Some(features) = latest {
+                write_output(&output, &features, &options)
+                    .context("Failed to write to output file")?;
+            }
+            return Ok(Tally {
+                succeeded,
+                failed,
+                total,
+                stopped: Some(stop),
+                failures: in_input_order(failures),
+            });
+        }
+    };
+    if cluster {
+        features = clustered_features(
+            &features,
+            cluster_precision,
+            null_island_epsilon,
+        );
+    }
+    if let Some(existing) = &existing {
+        features = merged_features(existing, &features);
+    }
+    if !cli.no_bbox {
+        features.bbox = bounding_box(&features, null_island_epsilon);
+    }
+
+    if streaming {
+        // the rest of the features, which weren't looked up, or failed to be
+        features
+            .features
+            .retain(|f| match streamed.get_mut(&f.to_string()) {
+                Some(n) if *n > 0 => {
+                    *n -= 1;
+                    false
+                }
+                _ => true,
+            });
+    }
+    write_output(&output, &features, &options)
+        .context("Failed to write to output file")?;
+    Ok(Tally {
+        succeeded,
+        failed,
+        total,
+        stopped: None,
+        failures: in_input_order(failures),
+    })
+}
+
+/// The features sorted by their index in the input, since lookups can finish
+/// in any order.
+fn in_input_order(mut features: Vec<(usize, Feature)>) -> Vec<Feature> {
+    features.sort_by_key(|(i, _)| *i);
+    features.into_iter().map(|(_, f)| f).collect()
+}
+
+/// Start or connect to the WebDriver servers, and start a session for each of
+/// the lookups run at once, taking turns between the servers. Each server
+/// gets at least one session.
+async fn connect_pool(
+    cli: &Cli,
+) -> anyhow::Result<(ClientPool, Option<Child>)> {
+    let (webdriver_urls, driver) = start_webdriver(cli).await?;
+    for webdriver_url in &webdriver_urls {
+        check_reachable(webdriver_url, cli.browser).await?;
+    }
+    let sessions = cli.concurrency.max(webdriver_urls.len());
+    let mut clients = vec![];
+    for webdriver_url in webdriver_urls.iter().cycle().take(sessions) {
+        match connect(cli, webdriver_url).await {
+            Ok(c) => clients.push((c, Some(webdriver_url.clone()))),
+            Err(e) => {
+                // don't leave the sessions that did connect running
+                for (c, _) in clients {
+                    let _ = c.close().await;
+                }
+                return Err(e).with_context(|| {
+                    format!(
+                        "Failed to connect to WebDriver at {webdriver_url}. \
+                        Is {} running?",
+                        cli.browser.driver()
+                    )
+                });
+            }
+        }
+    }
+    Ok((ClientPool::with_endpoints(clients), driver))
+}
+
+/// Check that something is listening at the WebDriver url, to explain how to
+/// start a WebDriver server if not.
+async fn check_reachable(
+    webdriver_url: &str,
+    browser: Browser,
+) -> anyhow::Result<()> {
+    let url = Url::parse(webdriver_url)
+        .with_context(|| format!("Invalid WebDriver url {webdriver_url}"))?;
+    let (Some(host), Some(port)) =
+        (url.host_str(), url.port_or_known_default())
+    else {
+        bail!("WebDriver url {webdriver_url} has no host or port");
+    };
+    if let Err(e) = TcpStream::connect((host, port)).await {
+        let driver = browser.driver();
+        bail!(
+            "Could not reach a WebDriver server at {webdriver_url} ({e}). \
+            Start one in another terminal with `{driver} --port={port}`, or \
+            pass --spawn-driver to start it automatically. See \
+            https://github.com/scoria-team/gmaps-coords#usage for setup help"
+        );
+    }
+    Ok(())
+}
+
+/// The urls of the WebDriver servers to connect to, and the server if it was
+/// spawned with --spawn-driver, which is killed when dropped.
+async fn start_webdriver(
+    cli: &Cli,
+) -> anyhow::Result<(Vec<String>, Option<Child>)> {
+    let mut driver = None;
+    let port = match cli.spawn_driver {
+        true => {
+            let port = match cli.port {
+                Some(port) => port,
+                None => free_port().context("Failed to find a free port")?,
+            };
+            driver = Some(spawn_driver(cli.browser, port).await?);
+            port
+        }
+        false => cli.port.unwrap_or(4444),
+    };
+    let webdriver_urls = cli
+        .webdriver_url
+        .iter()
+        .map(|url| match url.path().ends_with('/') {
+            true => url.to_string(),
+            // without a trailing slash, the last segment of a path like
+            // /wd/hub would be replaced by each command's path
+            false => format!("{url}/"),
+        })
+        .collect::<Vec<_>>();
+    match webdriver_urls.is_empty() {
+        true => Ok((vec![format!("http://localhost:{port}")], driver)),
+        false => Ok((webdriver_urls, driver)),
+    }
+}
+
+/// Start a browser session on the WebDriver server, resizing its window if
+/// asked to.
+async fn connect(
+    cli: &Cli,
+    webdriver_url: &str,
+) -> Result<Client, fantoccini::error::NewSessionError> {
+    let opts = cli.browser.capabilities(
+        !cli.noheadless,
+        cli.user_agent.as_deref(),
+        cli.proxy.as_ref(),
+    );
+    let c = ClientBuilder::native()
+        .capabilities(opts)
+        .connect(webdriver_url)
+        .await?;
+    if let Some((width, height)) = cli.window_size {
+        if let Err(e) = c.set_window_size(width, height).await {
+            warn!(
+                "Failed to resize the browser window with error {e}. \
+                Continuing."
+            );
+        }
+    }
+    Ok(c)
+}
+
+/// Check each step of looking up a place, from reaching the WebDriver server
+/// to finding the coordinates, stopping at the first that fails.
+async fn doctor(cli: &Cli) -> anyhow::Result<ExitCode> {
+    let driver_name = cli.browser.driver();
+    let started = start_webdriver(cli).await;
+    let reachable = async {
+        let (webdriver_urls, driver) = started?;
+        for webdriver_url in &webdriver_urls {
+            check_reachable(webdriver_url, cli.browser).await?;
+        }
+        anyhow::Ok((webdriver_urls, driver))
+    };
+    let Some((webdriver_urls, driver)) = check_step(
+        "Reach the WebDriver server",
+        reachable.await,
+        "Check --port or --webdriver-url, or pass --spawn-driver",
+    ) else {
+        return Ok(ExitCode::FAILURE);
+    };
+    // the session is only started on the first server
+    let webdriver_url = &webdriver_urls[0];
+    let Some(c) = check_step(
+        "Start a browser session",
+        connect(cli, webdriver_url).await,
+        &format!(
+            "Check that the browser is installed, and that the server is \
+            {driver_name}, matching --browser"
+        ),
+    ) else {
+        return Ok(ExitCode::FAILURE);
+    };
+    let lookup = LookupOptions {
+        timeout: Duration::from_secs(cli.timeout_secs),
+        accept_consent: cli.accept_consent,
+        ..LookupOptions::default()
+    };
+    let mut passed = check_step(
+        "Load Google Maps",
+        c.goto(DOCTOR_URL).await,
+        "Check the internet connection, and --proxy if set",
+    )
+    .is_some();
+    if passed {
+        let found = check_step(
+            "Find a place's coordinates",
+            get_coords_for_url(&c, DOCTOR_URL, &lookup).await,
+            "Try a desktop browser's --user-agent, a longer --timeout-secs, or \
+            --noheadless to see the page, with --accept-consent if it asks \
+            for cookie consent",
+        );
+        if let Some((coords, resolution)) = found {
+            println!(
+                "      {DOCTOR_URL} is at {}, {}, found by {}",
+                coords.lat,
+                coords.lng,
+                resolution.name()
+            );
+        }
+        passed = found.is_some();
+    }
+
+    let _ = c.close().await;
+    if let Some(mut driver) = driver {
+        let _ = driver.kill().await;
+    }
+    Ok(match passed {
+        true => ExitCode::SUCCESS,
+        false => ExitCode::FAILURE,
+    })
+}
+
+/// Print whether a step of `doctor` passed, and how to fix it if not.
+fn check_step<T, E: fmt::Display>(
+    step: &str,
+    result: Result<T, E>,
+    hint: &str,
+) -> Option<T> {
+    match result {
+        Ok(value) => {
+            println!("ok    {step}");
+            Some(value)
+        }
+        Err(e) => {
+            println!("FAIL  {step}: {e}");
+            println!("      {hint}");
+            None
+        }
+    }
+}
+
+/// A port on localhost that nothing is listening on.
+fn free_port() -> io::Result<u16> {
+    Ok(TcpListener::bind(("127.0.0.1", 0))?.local_addr()?.port())
+}
+
+/// Start the WebDriver server for the browser on the port, and wait for it to
+/// start listening.
+async fn spawn_driver(browser: Browser, port: u16) -> anyhow::Result<Child> {
+    let driver = browser.driver();
+    let mut child = Command::new(driver)
+        .arg(format!("--port={port}"))
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .kill_on_drop(true)
+        .spawn()
+        .map_err(|e| match e.kind() {
+            io::ErrorKind::NotFound => anyhow::anyhow!(
+                "Could not find {driver} on the PATH. Install it, or start a \
+                WebDriver server yourself and leave out --spawn-driver"
+            ),
+            _ => anyhow::Error::new(e)
+                .context(format!("Failed to start {driver}")),
+        })?;
+    let deadline = Instant::now() + DRIVER_STARTUP_TIMEOUT;
+    loop {
+        if TcpStream::connect(("127.0.0.1", port)).await.is_ok() {
+            return Ok(child);
+        }
+        if let Some(status) = child.try_wait()? {
+            bail!("{driver} exited with {status} before it started listening");
+        }
+        if Instant::now() >= deadline {
+            bail!(
+                "{driver} didn't start listening on port {port} within {} \
+                seconds",
+                DRIVER_STARTUP_TIMEOUT.as_secs()
+            );
+        }
+        sleep(Duration::from_millis(100)).await;
+    }
+}
+
+/// Writes log lines to stderr without garbling the progress bar.
+struct LogWriter(ProgressBar);
+
+impl Write for LogWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.suspend(|| io::stderr().write(buf))
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        io::stderr().flush()
+    }
+}
+
+/// The places read from an input file.
+enum Input {
+    /// A GeoJSON file, which may already have coordinates for some places.
+    Geojson(FeatureCollection),
+    /// A CSV or KML file of places without coordinates.
+    Records(Vec<Record>),
+}
+
+impl Input {
+    /// The url of each place that has one.
+    fn urls(&self) -> Vec<&str> {
+        match self {
+            Input::Geojson(feature_collection) => feature_collection
+                .features
+                .iter()
+                .filter_map(|f| f.property("google_maps_url")?.as_str())
+                .collect(),
+            Input::Records(records) => {
+                records.iter().map(|r| r.url.as_str()).collect()
+            }
+        }
+    }
+
+    /// The number of places, and the number of distinct urls that will be
+    /// looked up.
+    fn count_lookups(&self, lookup: &LookupOptions) -> (usize, usize) {
+        let total = match self {
+            Input::Geojson(feature_collection) => {
+                feature_collection.features.len()
+            }
+            Input::Records(records) => records.len(),
+        };
+        (total, self.lookups(lookup).len())
+    }
+
+    /// The number of distinct urls that will be looked up with the browser,
+    /// rather than from the cache or the url itself.
+    fn browser_lookups(&self, lookup: &LookupOptions) -> usize {
+        self.lookups(lookup)
+            .into_iter()
+            .filter(|&(url, fetch_name)| needs_browser(url, fetch_name, lookup))
+            .count()
+    }
+
+    /// The distinct urls that will be looked up, in the order they're first
+    /// in the input, with whether the place's name is fetched too.
+    fn lookups(&self, lookup: &LookupOptions) -> Vec<(&str, bool)> {
+        let places: Vec<(&str, bool)> = match self {
+            Input::Geojson(feature_collection) => feature_collection
+                .features
+                .iter()
+                .filter_map(|f| Some((lookup_url(f, lookup)?, false)))
+                .collect(),
+            Input::Records(records) => records
+                .iter()
+                .filter(|r| r.coords.is_none() || lookup.force_refetch)
+                .map(|r| {
+                    let fetch_name =
+                        lookup.fetch_names && r.title.trim().is_empty();
+                    (r.url.as_str(), fetch_name)
+                })
+                .collect(),
+        };
+        let mut lookups: Vec<(&str, bool)> = vec![];
+        let mut positions: HashMap<&str, usize> = HashMap::new();
+        for (url, fetch_name) in places {
+            if lookup.resolved.contains_key(url) {
+                continue;
+            }
+            match positions.get(url) {
+                Some(&pos) => lookups[pos].1 |= fetch_name,
+                None => {
+                    positions.insert(url, lookups.len());
+                    lookups.push((url, fetch_name));
+                }
+            }
+        }
+        lookup.selected(lookups)
+    }
+
+    /// Remove the tracking parameters from every place's url.
+    fn clean_urls(&mut self) {
+        match self {
+            Input::Geojson(feature_collection) => {
+                for feature in &mut feature_collection.features {
+                    let url = feature
+                        .property("google_maps_url")
+                        .and_then(|v| v.as_str())
+                        .map(clean_url);
+                    if let Some(url) = url {
+                        feature.set_property("google_maps_url", url);
+                    }
+                }
+            }
+            Input::Records(records) => {
+                for record in records {
+                    record.url = clean_url(&record.url);
+                }
+            }
+        }
+    }
+
+    /// Set the properties on every place, replacing any with the same key.
+    fn set_properties(&mut self, properties: &[(String, String)]) {
+        for (key, value) in properties {
+            match self {
+                Input::Geojson(feature_collection) => {
+                    for feature in &mut feature_collection.features {
+                        feature.set_property(key.clone(), value.clone());
+                    }
+                }
+                // the properties a record's own fields become replace those
+                // fields, so they aren't shadowed by them
+                Input::Records(records) => {
+                    for record in records {
+                        let value = value.clone();
+                        match key.as_str() {
+                            "name" => record.title = value,
+                            "google_maps_url" => record.url = value,
+                            "note" => record.note = Some(value),
+                            "comment" => record.comment = Some(value),
+                            _ => record.set_extra(key, value),
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Read the places in the input file in the format, and the columns of a CSV
+/// input in order.
+fn read_input(
+    path: &Path,
+    format: InputFormat,
+    csv_columns: &CsvColumns,
+) -> Result<(Input, Vec<String>)> {
+    Ok(match format {
+        InputFormat::Csv => {
+            let (records, columns) = read_csv(path, csv_columns)?;
+            (Input::Records(records), columns)
+        }
+        InputFormat::Kml => (Input::Records(read_kml(path)?), vec![]),
+        InputFormat::Geojson => (Input::Geojson(read_geojson(path)?), vec![]),
+    })
+}
+
+/// Parse the url of an HTTP or SOCKS proxy, which needs a host and port.
+fn parse_proxy(s: &str) -> Result<Url, String> {
+    let url = Url::parse(s).map_err(|e| e.to_string())?;
+    if !matches!(
+        url.scheme(),
+        "http" | "https" | "socks" | "socks4" | "socks5"
+    ) {
+        return Err("expected an http, https, socks4 or socks5 url".into());
+    }
+    if url.host_str().is_none() || url.port_or_known_default().is_none() {
+        return Err("expected a host and port, e.g. socks5://proxy:1080".into());
+    }
+    Ok(url)
+}
+
+/// Parse a property to set on every place, in the form "KEY=VALUE".
+fn parse_set_property(s: &str) -> Result<(String, String), String> {
+    match s.split_once('=') {
+        Some((key, value)) if !key.trim().is_empty() => {
+            Ok((key.trim().to_string(), value.to_string()))
+        }
+        _ => Err("expected KEY=VALUE, e.g. source=takeout-2024".into()),
+    }
+}
+
+/// Parse a window size in the form "WIDTHxHEIGHT".
+fn parse_window_size(s: &str) -> Result<(u32, u32), String> {
+    let size = s.split_once(['x', 'X']).and_then(|(width, height)| {
+        Some((width.trim().parse().ok()?, height.trim().parse().ok()?))
+    });
+    match size {
+        Some((width, height)) if width > 0 && height > 0 => Ok((width, height)),
+        _ => Err("expected WIDTHxHEIGHT, e.g. 1920x1080".into()),
+    }
+}
+
+/// Parse a single character for reading CSV files, such as the field
+/// delimiter, allowing "\t" to be spelled out.
+fn parse_csv_char(s: &str) -> Result<u8, String> {
+    match s {
+        "\\t" => Ok(b'\t'),
+        _ => match s.as_bytes() {
+            [b] if b.is_ascii() => Ok(*b),
+            _ => Err("must be a single ASCII character".into()),
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn set_properties_replace_the_same_keys_for_every_input() {
+        let record = Record {
+            title: "Cafe".into(),
+            note: None,
+            url: "https://maps.app.goo.gl/x".into(),
+            comment: None,
+            extra: vec![("source".into(), "old".into())],
+            coords: None,
+        };
+        let properties = [
+            ("name".to_string(), "Renamed".to_string()),
+            ("note".to_string(), "Set".to_string()),
+            ("source".to_string(), "takeout".to_string()),
+        ];
+        let mut records = Input::Records(vec![record.clone()]);
+        let mut geojson = Input::Geojson(FeatureCollection {
+            bbox: None,
+            features: vec![Feature::from(record)],
+            foreign_members: None,
+        });
+        records.set_properties(&properties);
+        geojson.set_properties(&properties);
+        let (Input::Records(records), Input::Geojson(geojson)) =
+            (records, geojson)
+        else {
+            unreachable!()
+        };
+        let from_records = Feature::from(records[0].clone());
+        assert_eq!(from_records, geojson.features[0]);
+        assert_eq!(from_records.property("name").unwrap(), "Renamed");
+        assert_eq!(from_records.property("note").unwrap(), "Set");
+        assert_eq!(from_records.property("source").unwrap(), "takeout");
+    }
+}
@@ -0,0 +1,765 @@
+//! Reading places from CSV files, and writing them back out as CSV.
+
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
+    io::Read,
+    path::Path,
+};
+
+use clap::ValueEnum;
+use csv::StringRecord;
+use futures::StreamExt;
+use geojson::{
+    Feature, FeatureCollection, Geometry, JsonObject, JsonValue, Value,
+};
+use tracing::warn;
+
+use crate::{
+    driver::{coords_stream, next_step, ClientPool, LookupOptions},
+    files::{format_extension, open_input},
+    geojson::{
+        failed_feature, is_null_island, place_feature, NULL_ISLAND_EPSILON,
+    },
+    output::round_all,
+    page::Page,
+    GmapsError, LatLng, Progress, Result,
+};
+
+// The columns holding the coordinates of each place in CSV output.
+const COORD_COLUMNS: [&str; 2] = ["Latitude", "Longitude"];
+
+// How many decimal places coordinates are compared to when clustering places
+// with the same coordinates, about 10cm, unless a coordinate precision is set.
+pub(crate) const CLUSTER_PRECISION: u32 = 6;
+
+/// A character encoding of CSV files.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, ValueEnum)]
+pub enum Encoding {
+    #[default]
+    #[value(name = "utf-8", alias = "utf8")]
+    Utf8,
+    /// ISO-8859-1, as saved by some older spreadsheet programs
+    #[value(alias = "iso-8859-1")]
+    Latin1,
+}
+
+/// Convert the features to CSV rows of their properties, in the given columns
+/// that the features have, e.g. those of a CSV input, then in columns sorted
+/// by name, followed by Latitude and Longitude columns. These are empty for
+/// features without a point, or that are missing coordinate data.
+pub(crate) fn to_csv(
+    features: &FeatureCollection,
+    null_island_epsilon: f64,
+    first_columns: &[String],
+) -> Result<String> {
+    let mut rest: BTreeSet<&str> = features
+        .features
+        .iter()
+        .flat_map(|f| f.properties.iter().flat_map(|p| p.keys()))
+        .map(String::as_str)
+        .filter(|key| !COORD_COLUMNS.contains(key))
+        .collect();
+    let mut columns: Vec<&str> = first_columns
+        .iter()
+        .filter_map(|column| rest.take(column.as_str()))
+        .collect();
+    columns.extend(rest);
+    let mut wtr = csv::Writer::from_writer(vec![]);
+    wtr.write_record(columns.iter().chain(&COORD_COLUMNS))?;
+    for feature in &features.features {
+        let mut row: Vec<String> = columns
+            .iter()
+            .map(|key| match feature.property(key) {
+                Some(JsonValue::String(s)) => s.clone(),
+                Some(JsonValue::Null) | None => String::new(),
+                Some(value) => value.to_string(),
+            })
+            .collect();
+        let coords = match feature.geometry {
+            Some(Geometry {
+                value: Value::Point(ref coords),
+                ..
+            }) if !is_null_island(coords, null_island_epsilon) => {
+                LatLng::from_geojson_point(coords)
+            }
+            _ => None,
+        };
+        match coords {
+            Some(LatLng { lat, lng, .. }) => {
+                row.extend([lat.to_string(), lng.to_string()]);
+            }
+            None => row.extend([String::new(), String::new()]),
+        }
+        wtr.write_record(&row)?;
+    }
+    let bytes = wtr.into_inner().map_err(|e| e.into_error())?;
+    Ok(String::from_utf8(bytes).expect("CSV of strings is valid UTF-8"))
+}
+
+/// Read the rows of a CSV output back into features, with a point if they
+/// have coordinates. Empty cells are left out of the properties.
+pub(crate) fn csv_features(s: &str) -> Result<Vec<Feature>> {
+    let mut rdr = csv::Reader::from_reader(s.as_bytes());
+    let headers = rdr.headers()?.clone();
+    let mut features = vec![];
+    for row in rdr.records() {
+        let row = row?;
+        let mut properties = JsonObject::new();
+        let (mut lat, mut lng) = (None, None);
+        for (header, value) in headers.iter().zip(row.iter()) {
+            match header {
+                _ if header == COORD_COLUMNS[0] => lat = value.parse().ok(),
+                _ if header == COORD_COLUMNS[1] => lng = value.parse().ok(),
+                _ if value.is_empty() => {}
+                _ => {
+                    properties.insert(header.into(), value.into());
+                }
+            }
+        }
+        let geometry = match (lat, lng) {
+            (Some(lat), Some(lng)) => {
+                let coords = LatLng {
+                    lat,
+                    lng,
+                    alt: None,
+                };
+                Some(Value::Point(coords.to_geojson_point()).into())
+            }
+            _ => None,
+        };
+        features.push(Feature {
+            geometry,
+            properties: Some(properties),
+            ..Default::default()
+        });
+    }
+    Ok(features)
+}
+
+/// A place read from a CSV row, from the columns named by [`CsvColumns`].
+#[derive(Debug, Clone)]
+pub struct Record {
+    pub title: String,
+    pub note: Option<String>,
+    pub url: String,
+    pub comment: Option<String>,
+    /// Other columns to carry into the output, as (header, value) pairs.
+    pub extra: Vec<(String, String)>,
+    /// The coordinates already in the row, if any, so it isn't looked up.
+    pub coords: Option<LatLng>,
+}
+
+/// The names of the CSV columns that hold each field of a record, and how
+/// the fields are separated.
+#[derive(Debug, Clone)]
+pub struct CsvColumns {
+    pub title: String,
+    pub note: String,
+    pub url: String,
+    pub comment: String,
+    /// The columns holding coordinates the place already has, if any.
+    pub latitude: String,
+    pub longitude: String,
+    /// How close to null island (0, 0), in degrees, coordinates in those
+    /// columns are still treated as missing.
+    pub null_island_epsilon: f64,
+    /// Whether to keep every other column as an extra field of the record.
+    pub extra: bool,
+    /// The field delimiter. Defaults to tab for .tsv files and comma otherwise.
+    pub delimiter: Option<u8>,
+    /// Lines starting with this character are skipped, if given.
+    pub comment_char: Option<u8>,
+    pub encoding: Encoding,
+}
+
+impl Default for CsvColumns {
+    fn default() -> Self {
+        CsvColumns {
+            title: "Title".into(),
+            note: "Note".into(),
+            url: "URL".into(),
+            comment: "Comment".into(),
+            latitude: COORD_COLUMNS[0].into(),
+            longitude: COORD_COLUMNS[1].into(),
+            null_island_epsilon: NULL_ISLAND_EPSILON,
+            extra: false,
+            delimiter: None,
+            comment_char: None,
+            encoding: Encoding::Utf8,
+        }
+    }
+}
+
+/// Read a CSV file of locations without coordinates, and the names of its
+/// columns in order.
+pub(crate) fn read_csv(
+    input_path: &Path,
+    columns: &CsvColumns,
+) -> Result<(Vec<Record>, Vec<String>)> {
+    let delimiter =
+        columns.delimiter.unwrap_or_else(|| {
+            match format_extension(input_path) {
+                Some("tsv") => b'\t',
+                _ => b',',
+            }
+        });
+    let mut bytes = vec![];
+    open_input(input_path)?.read_to_end(&mut bytes)?;
+    // some exports start with a UTF-8 byte order mark
+    if let Some(rest) = bytes.strip_prefix(b"\xef\xbb\xbf") {
+        bytes = rest.to_vec();
+    }
+    if columns.encoding == Encoding::Latin1 {
+        // each Latin-1 byte is the Unicode code point of the same value
+        bytes = bytes.iter().map(|&b| b as char).collect::<String>().into();
+    }
+    let mut rdr = csv::ReaderBuilder::new()
+        .delimiter(delimiter)
+        .comment(columns.comment_char)
+        .from_reader(bytes.as_slice());
+    let headers = rdr.headers()?.clone();
+    // fail once up front, rather than for every row
+    let missing: Vec<String> = [&columns.title, &columns.url]
+        .into_iter()
+        .filter(|column| !headers.iter().any(|h| h == *column))
+        .map(|column| format!("{column:?}"))
+        .collect();
+    if !missing.is_empty() {
+        return Err(GmapsError::MissingColumns {
+            missing,
+            found: headers.iter().map(|h| format!("{h:?}")).collect(),
+        });
+    }
+
+    let mut records = vec![];
+    for result in rdr.records() {
+        // the line the row starts on, to find it in the file
+        let line = match &result {
+            Ok(row) => row.position(),
+            Err(e) => e.position(),
+        }
+        .map(|position| position.line());
+        match result
+            .map_err(GmapsError::from)
+            .and_then(|row| record_from_row(&headers, &row, columns))
+        {
+            Ok(record) => records.push(record),
+            Err(e) => {
+                let line = match line {
+                    Some(line) => format!(" on line {line}"),
+                    None => String::new(),
+                };
+                warn!(
+                    "Failed to parse CSV record{line} with error {e}. \
+                    Continuing."
+                );
+            }
+        };
+    }
+    Ok((records, headers.iter().map(String::from).collect()))
+}
+
+/// Read a record from a CSV row, using the header to find the columns. The
+/// title and url columns are required, but the others are optional. Rows
+/// without valid coordinates in the latitude and longitude columns, or near
+/// null island, are left to be looked up.
+fn record_from_row(
+    headers: &StringRecord,
+    row: &StringRecord,
+    columns: &CsvColumns,
+) -> Result<Record> {
+    let field = |column: &str| {
+        headers
+            .iter()
+            .position(|h| h == column)
+            .and_then(|i| row.get(i))
+    };
+    let required = |column: &str| match field(column) {
+        Some(value) => Ok(value.to_string()),
+        None => Err(GmapsError::MissingColumn(column.to_string())),
+    };
+    let optional = |column: &str| {
+        field(column).filter(|v| !v.is_empty()).map(String::from)
+    };
+    let coordinate =
+        |column: &str| field(column).and_then(|v| v.trim().parse::<f64>().ok());
+    let coords = match (
+        coordinate(&columns.latitude),
+        coordinate(&columns.longitude),
+    ) {
+        (Some(lat), Some(lng))
+            if (-90.0..=90.0).contains(&lat)
+                && (-180.0..=180.0).contains(&lng)
+                && !is_null_island(
+                    &[lng, lat],
+                    columns.null_island_epsilon,
+                ) =>
+        {
+            Some(LatLng {
+                lat,
+                lng,
+                alt: None,
+            })
+        }
+        _ => None,
+    };
+    let handled = [
+        &columns.title,
+        &columns.note,
+        &columns.url,
+        &columns.comment,
+        &columns.latitude,
+        &columns.longitude,
+    ];
+    let extra = match columns.extra {
+        true => headers
+            .iter()
+            .zip(row.iter())
+            .filter(|(h, _)| !handled.iter().any(|c| c == h))
+            .map(|(h, v)| (h.to_string(), v.to_string()))
+            .collect(),
+        false => vec![],
+    };
+    Ok(Record {
+        title: required(&columns.title)?,
+        note: optional(&columns.note),
+        url: required(&columns.url)?,
+        comment: optional(&columns.comment),
+        extra,
+        coords,
+    })
+}
+
+/// Convert CSV records of locations without coordinates to GeoJSON by looking
+/// up the locations.
+///
+/// Records with coordinates of their own aren't looked up, unless
+/// `force_refetch` is set. The features are in the same order as the records,
+/// however many are looked up at once. Records whose coordinates can't be
+/// found are placed at null island (0, 0), the same as missing coordinates in
+/// Google's GeoJSON exports, or left out if `only_changed_places` is set.
+/// Records looked up get a `resolved_at` property with the time if
+/// `stamp_time` is set, and a `resolved_via` property with how they were found
+/// if `annotate_method` is set. `on_progress` is called with the collection so
+/// far each time a place has been looked up.
+pub async fn records_to_geojson<P: Page>(
+    pool: &ClientPool<P>,
+    mut records: Vec<Record>,
+    only_changed_places: bool,
+    lookup: &LookupOptions,
+    mut on_progress: impl FnMut(Progress),
+) -> FeatureCollection {
+    let mut found = BTreeMap::new();
+    // the records sharing each url, which is only looked up once
+    let mut indices: Vec<Vec<usize>> = vec![];
+    let mut urls = vec![];
+    let mut fetch_names = vec![];
+    let mut positions: HashMap<&str, usize> = HashMap::new();
+    for (i, record) in records.iter().enumerate() {
+        let fetch_name = lookup.fetch_names && record.title.trim().is_empty();
+        let coords = record
+            .coords
+            .as_ref()
+            .filter(|_| !lookup.force_refetch)
+            .or_else(|| lookup.resolved.get(&record.url));
+        match coords {
+            Some(coords) => {
+                found.insert(i, *coords);
+            }
+            None => match positions.get(record.url.as_str()) {
+                Some(&pos) => {
+                    indices[pos].push(i);
+                    fetch_names[pos] |= fetch_name;
+                }
+                None => {
+                    positions.insert(record.url.as_str(), urls.len());
+                    indices.push(vec![i]);
+                    urls.push(record.url.clone());
+                    fetch_names.push(fetch_name);
+                }
+            },
+        }
+    }
+    let indices = lookup.selected(indices);
+    let urls = lookup.selected(urls);
+    let fetch_names = lookup.selected(fetch_names);
+
+    // the input index of each output feature, to update them in place
+    let (mut feature_collection, mut output_indices) =
+        found_records_to_geojson(&records, &found, only_changed_places);
+    let (mut succeeded, mut failed) = (0, 0);
+    let mut results = coords_stream(pool, &urls, &fetch_names, lookup);
+    while let Some((i, result)) = results.next().await {
+        let mut found_feature = None;
+        let mut failures = vec![];
+        match result {
+            Ok((coords, resolution, name)) => {
+                succeeded += 1;
+                let resolved_at = lookup.resolved_at();
+                for &index in &indices[i] {
+                    let record = &mut records[index];
+                    if let (Some(name), true) =
+                        (&name, record.title.trim().is_empty())
+                    {
+                        record.title = name.clone();
+                    }
+                    if let Some(resolved_at) = &resolved_at {
+                        record.set_extra("resolved_at", resolved_at.clone());
+                    }
+                    if lookup.annotate_method {
+                        let via = resolution.name().to_string();
+                        record.set_extra("resolved_via", via);
+                    }
+                    place_feature(
+                        &mut feature_collection.features,
+                        &mut output_indices,
+                        index,
+                        record.clone().into_point_feature(coords),
+                    );
+                }
+                found_feature = Some(
+                    records[indices[i][0]].clone().into_point_feature(coords),
+                );
+            }
+            Err(e) => {
+                failed += 1;
+                warn!(
+                    "Failed to retrieve coordinates for record {:?} with \
+                    error {e:#}. {}",
+                    records[indices[i][0]],
+                    next_step(lookup)
+                );
+                failures = indices[i]
+                    .iter()
+                    .map(|&index| {
+                        let feature = Feature::from(records[index].clone());
+                        (index, failed_feature(feature, &e))
+                    })
+                    .collect();
+            }
+        };
+        on_progress(Progress {
+            features: &feature_collection,
+            found: found_feature.as_ref(),
+            succeeded,
+            failed,
+            total: urls.len(),
+            failures: &failures,
+        });
+        if failed > 0 && lookup.fail_fast {
+            break;
+        }
+    }
+
+    feature_collection
+}
+
+/// Convert the records to GeoJSON with the coordinates found for them, by
+/// record index, along with the index of each record kept. The rest are at
+/// null island, unless only the records with coordinates are wanted.
+fn found_records_to_geojson(
+    records: &[Record],
+    found: &BTreeMap<usize, LatLng>,
+    only_changed_places: bool,
+) -> (FeatureCollection, Vec<usize>) {
+    let missing = LatLng {
+        lat: 0.0,
+        lng: 0.0,
+        alt: None,
+    };
+    let (features, indices) = records
+        .iter()
+        .enumerate()
+        .filter_map(|(i, record)| match found.get(&i) {
+            Some(coords) => Some((record.clone(), *coords, i)),
+            None => {
+                (!only_changed_places).then(|| (record.clone(), missing, i))
+            }
+        })
+        .map(|(record, coords, i)| (record.into_point_feature(coords), i))
+        .unzip();
+    let feature_collection = FeatureCollection {
+        features,
+        bbox: None,
+        foreign_members: None,
+    };
+    (feature_collection, indices)
+}
+
+/// Combine the point features with the same coordinates, when rounded to the
+/// number of decimal places, into the first of them. It gets a `count` of the
+/// features and their `names`. Other features, and those missing coordinate
+/// data, are left as they are.
+pub(crate) fn clustered_features(
+    features: &FeatureCollection,
+    precision: u32,
+    null_island_epsilon: f64,
+) -> FeatureCollection {
+    let mut clustered: Vec<Feature> = vec![];
+    // the names of each clustered feature, by its index
+    let mut names: Vec<Vec<JsonValue>> = vec![];
+    let mut positions: HashMap<Vec<u64>, usize> = HashMap::new();
+    for feature in &features.features {
+        let coords = match feature.geometry {
+            Some(Geometry {
+                value: Value::Point(ref coords),
+                ..
+            }) if !is_null_island(coords, null_island_epsilon) => coords,
+            _ => {
+                clustered.push(feature.clone());
+                names.push(vec![]);
+                continue;
+            }
+        };
+        let key = round_all(&coords[..coords.len().min(2)], precision)
+            .iter()
+            .map(|c| c.to_bits())
+            .collect();
+        let name = feature.property("name").cloned().unwrap_or(JsonValue::Null);
+        match positions.get(&key) {
+            Some(&pos) => names[pos].push(name),
+            None => {
+                positions.insert(key, clustered.len());
+                clustered.push(feature.clone());
+                names.push(vec![name]);
+            }
+        }
+    }
+    for (feature, names) in clustered.iter_mut().zip(names) {
+        if names.len() > 1 {
+            feature.set_property("count", names.len());
+            feature.set_property("names", names);
+        }
+    }
+    FeatureCollection {
+        features: clustered,
+        bbox: features.bbox.clone(),
+        foreign_members: features.foreign_members.clone(),
+    }
+}
+
+impl Record {
+    /// Convert the record to a GeoJSON feature with a point at the
+    /// coordinates.
+    pub fn into_point_feature(self, coords: LatLng) -> Feature {
+        Feature {
+            geometry: Some(Value::Point(coords.to_geojson_point()).into()),
+            ..self.into()
+        }
+    }
+
+    /// Set an extra field, replacing the value of a column of the same name.
+    pub(crate) fn set_extra(&mut self, key: &str, value: String) {
+        self.extra.retain(|(k, _)| k != key);
+        self.extra.push((key.to_string(), value));
+    }
+}
+
+/// Convert a CSV record to a GeoJSON feature without a geometry. Its fields
+/// become the properties, which the extra fields don't replace.
+impl From<Record> for Feature {
+    fn from(record: Record) -> Self {
+        let mut properties = JsonObject::new();
+        properties.insert("name".into(), record.title.into());
+        properties.insert("google_maps_url".into(), record.url.into());
+        if let Some(note) = record.note {
+            properties.insert("note".into(), note.into());
+        }
+        if let Some(comment) = record.comment {
+            properties.insert("comment".into(), comment.into());
+        }
+        for (key, value) in record.extra {
+            properties.entry(key).or_insert(value.into());
+        }
+        Feature {
+            properties: Some(properties),
+            ..Default::default()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, path::PathBuf};
+
+    use tokio::time::Duration;
+
+    use super::*;
+    use crate::page::fake::FakePage;
+
+    /// Write the bytes to a file of their own in the temporary directory.
+    fn temp_csv(name: &str, bytes: &[u8]) -> PathBuf {
+        let path = std::env::temp_dir()
+            .join(format!("gmaps-coords-{}-{name}.csv", std::process::id()));
+        fs::write(&path, bytes).unwrap();
+        path
+    }
+
+    #[test]
+    fn csv_output_keeps_the_input_column_order() {
+        let mut properties = JsonObject::new();
+        for (key, value) in [
+            ("Title", "Cafe"),
+            ("URL", "https://maps.app.goo.gl/x"),
+            ("Zone", "B"),
+            ("Area", "5"),
+            ("resolved_via", "url"),
+        ] {
+            properties.insert(key.into(), value.into());
+        }
+        let features = FeatureCollection {
+            bbox: None,
+            features: vec![Feature {
+                geometry: Some(Value::Point(vec![2.5, 1.5]).into()),
+                properties: Some(properties),
+                ..Default::default()
+            }],
+            foreign_members: None,
+        };
+        let columns = ["Title", "Note", "URL", "Comment", "Zone", "Area"]
+            .map(String::from);
+        let csv = to_csv(&features, NULL_ISLAND_EPSILON, &columns).unwrap();
+        assert_eq!(
+            csv,
+            "Title,URL,Zone,Area,resolved_via,Latitude,Longitude\n\
+            Cafe,https://maps.app.goo.gl/x,B,5,url,1.5,2.5\n"
+        );
+    }
+
+    #[test]
+    fn coordinate_columns_respect_the_null_island_epsilon() {
+        let headers =
+            StringRecord::from(vec!["Title", "URL", "Latitude", "Longitude"]);
+        let row =
+            StringRecord::from(vec!["Cafe", "u", "0.0000005", "-0.0000005"]);
+        let record = |null_island_epsilon| {
+            let columns = CsvColumns {
+                null_island_epsilon,
+                ..CsvColumns::default()
+            };
+            record_from_row(&headers, &row, &columns).unwrap()
+        };
+        assert_eq!(record(NULL_ISLAND_EPSILON).coords, None);
+        let coords = record(0.0).coords.unwrap();
+        assert_eq!((coords.lat, coords.lng), (0.0000005, -0.0000005));
+    }
+
+    #[test]
+    fn byte_order_mark_is_skipped() {
+        let path = temp_csv(
+            "bom",
+            b"\xef\xbb\xbfTitle,Note,URL,Comment\n\
+            Cafe,,https://maps.app.goo.gl/x,\n",
+        );
+        let (records, _) = read_csv(&path, &CsvColumns::default()).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].title, "Cafe");
+        assert_eq!(records[0].url, "https://maps.app.goo.gl/x");
+    }
+
+    #[test]
+    fn latin1_is_transcoded() {
+        // "Café" and "Zürich" in ISO-8859-1
+        let path = temp_csv(
+            "latin1",
+            b"Title,Note,URL,Comment\n\
+            Caf\xe9,Z\xfcrich,https://maps.app.goo.gl/x,\n",
+        );
+        let columns = CsvColumns {
+            encoding: Encoding::Latin1,
+            ..CsvColumns::default()
+        };
+        let (records, _) = read_csv(&path, &columns).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(records[0].title, "Café");
+        assert_eq!(records[0].note.as_deref(), Some("Zürich"));
+    }
+
+    #[tokio::test]
+    async fn refetched_rows_are_stamped_again() {
+        let path = temp_csv(
+            "restamp",
+            b"Title,URL,resolved_at,resolved_via,Latitude,Longitude\n\
+            A,\"https://maps.google.com/?q=1,2\",\
+            2020-01-01T00:00:00Z,cache,5,5\n",
+        );
+        let (records, _) = read_csv(&path, &CsvColumns::default()).unwrap();
+        fs::remove_file(&path).unwrap();
+        let lookup = LookupOptions {
+            force_refetch: true,
+            stamp_time: true,
+            annotate_method: true,
+            ..LookupOptions::default()
+        };
+        let features = records_to_geojson(
+            &ClientPool::empty(),
+            records,
+            false,
+            &lookup,
+            |_| {},
+        )
+        .await;
+        let feature = &features.features[0];
+        let geometry = feature.geometry.as_ref().unwrap();
+        assert_eq!(geometry.value, Value::Point(vec![2.0, 1.0]));
+        let resolved_at = feature.property("resolved_at").unwrap();
+        assert_ne!(resolved_at, "2020-01-01T00:00:00Z");
+        assert_eq!(feature.property("resolved_via").unwrap(), "q-param");
+    }
+
+    #[tokio::test]
+    async fn lookups_finishing_out_of_order_are_output_in_input_order() {
+        let place = "https://www.google.com/maps/place";
+        // the browser takes longest to load the first place
+        let delays = [("A", 100), ("B", 0), ("C", 50)];
+        let page = || {
+            delays
+                .iter()
+                .fold(FakePage::default(), |page, &(name, ms)| {
+                    let url = format!("{place}/{name}");
+                    page.redirect(&url, &[&format!("{url}/@1,2,17z")])
+                        .delay(&url, Duration::from_millis(ms))
+                })
+        };
+        let pool = ClientPool::new(vec![page(), page(), page()]);
+        let mut records: Vec<Record> = ["A", "B", "C"]
+            .iter()
+            .map(|name| Record {
+                title: name.to_string(),
+                note: None,
+                url: format!("{place}/{name}"),
+                comment: None,
+                extra: vec![],
+                coords: None,
+            })
+            .collect();
+        records.push(Record {
+            title: "D".into(),
+            url: format!("{place}/D/data=!3d3!4d4"),
+            ..records[0].clone()
+        });
+        let lookup = LookupOptions {
+            timeout: Duration::from_millis(500),
+            poll_interval: Duration::from_millis(5),
+            ..LookupOptions::default()
+        };
+        let name = |feature: &Feature| {
+            feature
+                .property("name")
+                .unwrap()
+                .as_str()
+                .unwrap()
+                .to_string()
+        };
+        let mut finished = vec![];
+        let features =
+            records_to_geojson(&pool, records, false, &lookup, |progress| {
+                finished.push(name(progress.found.unwrap()))
+            })
+            .await;
+        assert_eq!(finished, ["D", "B", "C", "A"]);
+        let names: Vec<_> = features.features.iter().map(name).collect();
+        assert_eq!(names, ["A", "B", "C", "D"]);
+    }
+}
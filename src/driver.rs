@@ -0,0 +1,1407 @@
+//! Looking up coordinates by driving a browser over WebDriver.
+
+use std::{
+    collections::{HashMap, VecDeque},
+    fmt, fs, io,
+    ops::Deref,
+    path::PathBuf,
+    sync::Mutex,
+};
+
+use clap::ValueEnum;
+use fantoccini::Client;
+use futures::{stream, Stream, StreamExt};
+use geojson::JsonObject;
+use serde::Serialize;
+use time::{format_description::well_known::Rfc3339, OffsetDateTime};
+use tokio::time::{sleep, sleep_until, Duration, Instant};
+use tracing::{debug, info, instrument, warn};
+use url::Url;
+
+use crate::{
+    extract::{
+        default_extractors, is_short_link, CoordExtractor, DataParamExtractor,
+        MapCenterExtractor, Provider, PLACE_ID_RE,
+    },
+    files::write_atomically,
+    geojson::NULL_ISLAND_EPSILON,
+    page::{Page, Selector},
+    BlockedError, Bounds, GmapsError, LatLng, Result, WebDriverError,
+};
+
+// The list of places shown for a search with several results, and the link to
+// each result's place page.
+const RESULTS_LIST_SELECTOR: &str = "div[role='feed']";
+const RESULT_LINK_SELECTOR: &str = "a[href*='/maps/place/']";
+
+// The map's canvas, which is rendered once the page is centered on the place.
+const MAP_CANVAS_SELECTOR: &str = "#scene canvas";
+
+// How long to wait before the first retry of a failed lookup. The wait doubles
+// with each retry.
+const RETRY_BACKOFF: Duration = Duration::from_secs(1);
+
+// The host of Google's cookie consent page, and its "Accept all" button.
+const CONSENT_HOST: &str = "consent.google.com";
+const CONSENT_ACCEPT_XPATH: &str = "//button[contains(., 'Accept all')]";
+
+/// How to tell when the page has found a place's coordinates.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum WaitStrategy {
+    /// Check the page's url repeatedly until it's centered on the place
+    #[default]
+    Poll,
+    /// Wait for the map to render, then read the url once, falling back to
+    /// polling if it isn't centered on the place yet
+    Element,
+}
+
+/// A browser that can be driven by a WebDriver server.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Browser {
+    /// Firefox, driven by geckodriver
+    Firefox,
+    /// Chrome, driven by chromedriver
+    Chrome,
+}
+
+impl Browser {
+    /// The WebDriver server executable for this browser.
+    pub fn driver(self) -> &'static str {
+        match self {
+            Browser::Firefox => "geckodriver",
+            Browser::Chrome => "chromedriver",
+        }
+    }
+
+    /// The WebDriver capabilities to start a session of this browser with,
+    /// optionally overriding its user agent and using a proxy. Headless mode
+    /// is set with each browser's own argument, and left out entirely when not
+    /// headless. The proxy must have a host and port, as from `parse_proxy`.
+    pub fn capabilities(
+        self,
+        headless: bool,
+        user_agent: Option<&str>,
+        proxy: Option<&Url>,
+    ) -> JsonObject {
+        let (name, options_key, headless_arg) = match self {
+            Browser::Firefox => ("firefox", "moz:firefoxOptions", "--headless"),
+            Browser::Chrome => {
+                ("chrome", "goog:chromeOptions", "--headless=new")
+            }
+        };
+        let mut options = JsonObject::new();
+        let mut args = vec![];
+        if headless {
+            args.push(headless_arg.to_string());
+        }
+        let mut prefs = JsonObject::new();
+        if let Some(user_agent) = user_agent {
+            match self {
+                Browser::Firefox => {
+                    prefs.insert(
+                        "general.useragent.override".into(),
+                        user_agent.into(),
+                    );
+                }
+                Browser::Chrome => {
+                    args.push(format!("--user-agent={user_agent}"));
+                }
+            }
+        }
+        if let Some(proxy) = proxy {
+            let host = proxy.host_str().unwrap_or_default();
+            let port = proxy.port_or_known_default().unwrap_or_default();
+            match self {
+                Browser::Firefox => {
+                    // manual proxy configuration
+                    prefs.insert("network.proxy.type".into(), 1.into());
+                    let socks = proxy.scheme().starts_with("socks");
+                    let kinds: &[&str] = match socks {
+                        true => &["socks"],
+                        false => &["http", "ssl"],
+                    };
+                    for kind in kinds {
+                        prefs.insert(
+                            format!("network.proxy.{kind}"),
+                            host.into(),
+                        );
+                        prefs.insert(
+                            format!("network.proxy.{kind}_port"),
+                            port.into(),
+                        );
+                    }
+                    if socks {
+                        let version = match proxy.scheme() {
+                            "socks4" => 4,
+                            _ => 5,
+                        };
+                        prefs.insert(
+                            "network.proxy.socks_version".into(),
+                            version.into(),
+                        );
+                        // so Google's hosts are looked up through the proxy too
+                        prefs.insert(
+                            "network.proxy.socks_remote_dns".into(),
+                            true.into(),
+                        );
+                    }
+                }
+                Browser::Chrome => {
+                    let scheme = match proxy.scheme() {
+                        "socks" => "socks5",
+                        scheme => scheme,
+                    };
+                    args.push(format!(
+                        "--proxy-server={scheme}://{host}:{port}"
+                    ));
+                }
+            }
+        }
+        if !prefs.is_empty() {
+            options.insert("prefs".into(), prefs.into());
+        }
+        if !args.is_empty() {
+            options.insert("args".into(), args.into());
+        }
+        let mut caps = JsonObject::new();
+        caps.insert("browserName".into(), name.into());
+        if !options.is_empty() {
+            caps.insert(options_key.into(), options.into());
+        }
+        caps
+    }
+}
+
+/// Options controlling how the coordinates of each place are looked up.
+#[derive(Debug)]
+pub struct LookupOptions {
+    /// How long to wait for the page to find the coordinates of a place.
+    pub timeout: Duration,
+    /// How long to wait for the page to find the coordinates of a place whose
+    /// url only has a place id.
+    pub place_id_timeout: Duration,
+    /// Whether to retry a lookup that timed out once, with double the timeout.
+    pub retry_timeout: bool,
+    /// How long to wait between checks of the page's url.
+    pub poll_interval: Duration,
+    /// How to tell when the page has found the coordinates.
+    pub wait_strategy: WaitStrategy,
+    /// Whether to wait for the page's coordinates to stop changing.
+    pub wait_settled: bool,
+    /// Coordinates found previously, consulted before going to the url.
+    pub cache: Option<CoordCache>,
+    /// Coordinates already found for urls, e.g. in the output of an earlier
+    /// run. Places with these urls aren't looked up at all.
+    pub resolved: HashMap<String, LatLng>,
+    /// How close to null island (0, 0), in degrees, a point is treated as
+    /// missing coordinate data.
+    pub null_island_epsilon: f64,
+    /// How many times to retry a lookup after a WebDriver error, waiting twice
+    /// as long before each retry.
+    pub max_retries: u32,
+    /// Spaces out going to urls, across every client.
+    pub rate_limiter: RateLimiter,
+    /// The map provider of the urls.
+    pub provider: Provider,
+    /// The ways of reading coordinates from urls, tried in order.
+    pub extractors: Vec<Box<dyn CoordExtractor>>,
+    /// Whether to accept Google's cookie consent page if it's shown.
+    pub accept_consent: bool,
+    /// Whether to use the first result of a search that lists several places.
+    pub first_result: bool,
+    /// The region places are expected to be in, if any.
+    pub bounds: Option<Bounds>,
+    /// Whether to stop looking up places after the first failure.
+    pub fail_fast: bool,
+    /// Whether to read the name of records with an empty title from the page.
+    pub fetch_names: bool,
+    /// Where to record how long each lookup took, if anywhere.
+    pub report: Option<LookupReport>,
+    /// How many of the places that need looking up to look up, if not all.
+    pub limit: Option<usize>,
+    /// How many of the places that need looking up to skip before the rest.
+    pub skip: usize,
+    /// Whether to look up places that already have coordinates too, without
+    /// reading them from the cache. The coordinates found are still cached.
+    pub force_refetch: bool,
+    /// Whether to add the time places were looked up to them, as a
+    /// `resolved_at` property.
+    pub stamp_time: bool,
+    /// Whether to add how places' coordinates were found to them, as a
+    /// `resolved_via` property.
+    pub annotate_method: bool,
+}
+
+impl LookupOptions {
+    /// The lookups to attempt, out of every one that's needed, in order.
+    pub(crate) fn selected<T>(&self, lookups: Vec<T>) -> Vec<T> {
+        lookups
+            .into_iter()
+            .skip(self.skip)
+            .take(self.limit.unwrap_or(usize::MAX))
+            .collect()
+    }
+
+    /// The coordinates cached for the url, unless places are being looked up
+    /// again regardless.
+    fn cached(&self, url: &str) -> Option<LatLng> {
+        match self.force_refetch {
+            true => None,
+            false => self.cache.as_ref()?.get(url),
+        }
+    }
+
+    /// The current time in RFC 3339 format, to stamp places just looked up
+    /// with, if they're stamped.
+    pub(crate) fn resolved_at(&self) -> Option<String> {
+        match self.stamp_time {
+            true => OffsetDateTime::now_utc().format(&Rfc3339).ok(),
+            false => None,
+        }
+    }
+}
+
+impl Default for LookupOptions {
+    fn default() -> Self {
+        LookupOptions {
+            timeout: Duration::from_secs(10),
+            place_id_timeout: Duration::from_secs(30),
+            retry_timeout: false,
+            poll_interval: Duration::from_millis(100),
+            wait_strategy: WaitStrategy::Poll,
+            wait_settled: false,
+            cache: None,
+            resolved: HashMap::new(),
+            null_island_epsilon: NULL_ISLAND_EPSILON,
+            max_retries: 3,
+            rate_limiter: RateLimiter::new(Duration::ZERO),
+            provider: Provider::Auto,
+            extractors: default_extractors(),
+            accept_consent: false,
+            first_result: false,
+            bounds: None,
+            fail_fast: false,
+            fetch_names: false,
+            report: None,
+            limit: None,
+            skip: 0,
+            force_refetch: false,
+            stamp_time: false,
+            annotate_method: false,
+        }
+    }
+}
+
+/// What happens after a failed lookup, for warning about it.
+pub(crate) fn next_step(lookup: &LookupOptions) -> &'static str {
+    match lookup.fail_fast {
+        true => "Stopping.",
+        false => "Continuing.",
+    }
+}
+
+/// Enforces a minimum delay between navigations, shared by every lookup so it
+/// holds for concurrent lookups too.
+#[derive(Debug)]
+pub struct RateLimiter {
+    min_delay: Duration,
+    next: tokio::sync::Mutex<Instant>,
+}
+
+impl RateLimiter {
+    /// Create a rate limiter allowing one navigation per `min_delay`.
+    pub fn new(min_delay: Duration) -> Self {
+        RateLimiter {
+            min_delay,
+            next: tokio::sync::Mutex::new(Instant::now()),
+        }
+    }
+
+    /// Wait until the next navigation is allowed.
+    async fn wait(&self) {
+        if self.min_delay.is_zero() {
+            return;
+        }
+        // holding the lock while sleeping queues up the other lookups
+        let mut next = self.next.lock().await;
+        sleep_until(*next).await;
+        *next = Instant::now() + self.min_delay;
+    }
+}
+
+/// A map of url to coordinates (lng, lat), saved to a JSON file every time a
+/// new entry is added.
+#[derive(Debug)]
+pub struct CoordCache {
+    path: PathBuf,
+    entries: Mutex<HashMap<String, Vec<f64>>>,
+}
+
+impl CoordCache {
+    /// Load the cache from a file, or start an empty one if it doesn't exist.
+    pub fn load(path: PathBuf) -> Result<Self> {
+        let entries = match fs::read_to_string(&path) {
+            Ok(s) => serde_json::from_str(&s)?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => HashMap::new(),
+            Err(e) => return Err(e.into()),
+        };
+        Ok(CoordCache {
+            path,
+            entries: Mutex::new(entries),
+        })
+    }
+
+    /// The cached coordinates for the url.
+    pub fn get(&self, url: &str) -> Option<LatLng> {
+        self.entries
+            .lock()
+            .unwrap()
+            .get(url)
+            .and_then(|coords| LatLng::from_geojson_point(coords))
+    }
+
+    /// Add coordinates for the url and save the cache file.
+    pub fn insert(&self, url: &str, coords: LatLng) -> Result<()> {
+        let mut entries = self.entries.lock().unwrap();
+        entries.insert(url.to_string(), coords.to_geojson_point());
+        // a crash can't corrupt the cache
+        write_atomically(&self.path, serde_json::to_string(&*entries)?)?;
+        Ok(())
+    }
+}
+
+/// How a place's coordinates were found.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LookupMethod {
+    /// From the coordinate cache.
+    Cache,
+    /// From the url itself, without loading the page.
+    Url,
+    /// By loading the page in the browser.
+    Browser,
+}
+
+/// How a place's coordinates were found, in more detail than its
+/// [`LookupMethod`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Resolution {
+    /// From the coordinate cache.
+    Cache,
+    /// From the url itself, by the extractor with this name.
+    Url(&'static str),
+    /// From the page in the browser, such as by the extractor with this name
+    /// reading the url it redirected to.
+    Browser(&'static str),
+}
+
+impl Resolution {
+    fn method(self) -> LookupMethod {
+        match self {
+            Resolution::Cache => LookupMethod::Cache,
+            Resolution::Url(_) => LookupMethod::Url,
+            Resolution::Browser(_) => LookupMethod::Browser,
+        }
+    }
+
+    /// The name of how the coordinates were found, for the `resolved_via`
+    /// property.
+    pub(crate) fn name(self) -> &'static str {
+        match self {
+            Resolution::Cache => "cache",
+            Resolution::Url(name) | Resolution::Browser(name) => name,
+        }
+    }
+}
+
+/// How long a successful lookup took.
+#[derive(Debug, Clone, Serialize)]
+pub struct LookupTiming {
+    pub url: String,
+    pub seconds: f64,
+    pub method: LookupMethod,
+}
+
+/// The timings of every successful lookup, for finding slow urls.
+#[derive(Debug)]
+pub struct LookupReport {
+    started: Instant,
+    timings: Mutex<Vec<LookupTiming>>,
+}
+
+/// Statistics about the lookups, from [`LookupReport::summary`].
+#[derive(Debug, Clone, Serialize)]
+pub struct ReportSummary {
+    pub total_seconds: f64,
+    pub lookups: usize,
+    pub mean_seconds: f64,
+    pub median_seconds: f64,
+    pub p95_seconds: f64,
+    pub from_cache: usize,
+    pub from_url: usize,
+    pub from_browser: usize,
+    pub slowest: Vec<LookupTiming>,
+}
+
+impl LookupReport {
+    /// Start timing a run.
+    pub fn new() -> Self {
+        LookupReport {
+            started: Instant::now(),
+            timings: Mutex::new(vec![]),
+        }
+    }
+
+    fn record(&self, url: &str, elapsed: Duration, method: LookupMethod) {
+        self.timings.lock().unwrap().push(LookupTiming {
+            url: url.to_string(),
+            seconds: elapsed.as_secs_f64(),
+            method,
+        });
+    }
+
+    /// Summarize the lookups so far.
+    pub fn summary(&self) -> ReportSummary {
+        let mut timings = self.timings.lock().unwrap().clone();
+        timings.sort_by(|a, b| b.seconds.total_cmp(&a.seconds));
+        let count =
+            |method| timings.iter().filter(|t| t.method == method).count();
+        // nearest rank, with the slowest lookups first
+        let percentile = |p: f64| match timings.len() {
+            0 => 0.0,
+            n => timings[((1.0 - p) * (n - 1) as f64).round() as usize].seconds,
+        };
+        ReportSummary {
+            total_seconds: self.started.elapsed().as_secs_f64(),
+            lookups: timings.len(),
+            mean_seconds: match timings.len() {
+                0 => 0.0,
+                n => timings.iter().map(|t| t.seconds).sum::<f64>() / n as f64,
+            },
+            median_seconds: percentile(0.5),
+            p95_seconds: percentile(0.95),
+            from_cache: count(LookupMethod::Cache),
+            from_url: count(LookupMethod::Url),
+            from_browser: count(LookupMethod::Browser),
+            slowest: timings.iter().take(5).cloned().collect(),
+        }
+    }
+}
+
+impl Default for LookupReport {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl fmt::Display for ReportSummary {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(
+            f,
+            "{} lookups in {:.2} seconds",
+            self.lookups, self.total_seconds
+        )?;
+        writeln!(
+            f,
+            "Per lookup: mean {:.2}s, median {:.2}s, p95 {:.2}s",
+            self.mean_seconds, self.median_seconds, self.p95_seconds
+        )?;
+        writeln!(
+            f,
+            "From the cache: {}, from the url: {}, from the browser: {}",
+            self.from_cache, self.from_url, self.from_browser
+        )?;
+        writeln!(f, "Slowest:")?;
+        for timing in &self.slowest {
+            writeln!(f, "  {:.2}s {}", timing.seconds, timing.url)?;
+        }
+        Ok(())
+    }
+}
+
+/// A set of connected WebDriver clients, or other [`Page`]s, that lookups are
+/// spread across, in turn.
+pub struct ClientPool<P = Client> {
+    /// Each client, with the url of the WebDriver server it's connected to if
+    /// known.
+    clients: Mutex<VecDeque<(P, Option<String>)>>,
+    size: usize,
+}
+
+impl<P> ClientPool<P> {
+    /// Create a pool from connected clients. The pool must not be empty.
+    pub fn new(clients: Vec<P>) -> Self {
+        Self::with_endpoints(clients.into_iter().map(|c| (c, None)).collect())
+    }
+
+    /// Create a pool from clients connected to the WebDriver server at each
+    /// url, which is logged with each lookup. The pool must not be empty.
+    pub fn with_endpoints(clients: Vec<(P, Option<String>)>) -> Self {
+        assert!(!clients.is_empty(), "ClientPool needs at least one client");
+        ClientPool {
+            size: clients.len(),
+            clients: Mutex::new(clients.into()),
+        }
+    }
+
+    /// The number of clients, and so the number of concurrent lookups.
+    pub fn len(&self) -> usize {
+        self.size
+    }
+
+    /// Whether the pool has no clients, which is only the case for an
+    /// [`empty`](ClientPool::empty) pool.
+    pub fn is_empty(&self) -> bool {
+        self.size == 0
+    }
+
+    /// Take the free client that has been free the longest out of the pool,
+    /// until the returned guard is dropped.
+    fn checkout(&self) -> Checkout<'_, P> {
+        let client = self
+            .clients
+            .lock()
+            .unwrap()
+            .pop_front()
+            .expect("More lookups in flight than clients in the pool");
+        Checkout {
+            pool: self,
+            client: Some(client),
+        }
+    }
+
+    /// Return a client to the pool.
+    fn checkin(&self, client: (P, Option<String>)) {
+        self.clients.lock().unwrap().push_back(client);
+    }
+}
+
+impl ClientPool {
+    /// A pool without any clients, for when no lookup needs the browser.
+    /// Checking a client out of it panics.
+    pub(crate) fn empty() -> Self {
+        ClientPool {
+            clients: Mutex::new(VecDeque::new()),
+            size: 0,
+        }
+    }
+
+    /// Close every client, even if closing one of them fails.
+    pub async fn close(self) -> Result<()> {
+        let mut result = Ok(());
+        for (c, _) in self.clients.into_inner().unwrap() {
+            if let Err(e) = c.close().await {
+                result = Err(GmapsError::from(e));
+            }
+        }
+        result
+    }
+}
+
+/// A client checked out of a [`ClientPool`]. It's returned to the pool when
+/// dropped, including when the lookup using it is cancelled, e.g. on Ctrl-C,
+/// so that [`ClientPool::close`] still closes its session.
+struct Checkout<'a, P> {
+    pool: &'a ClientPool<P>,
+    client: Option<(P, Option<String>)>,
+}
+
+impl<P> Checkout<'_, P> {
+    /// The url of the WebDriver server the client is connected to, if known.
+    fn endpoint(&self) -> Option<&str> {
+        self.client.as_ref()?.1.as_deref()
+    }
+}
+
+impl<P> Deref for Checkout<'_, P> {
+    type Target = P;
+
+    fn deref(&self) -> &P {
+        &self.client.as_ref().unwrap().0
+    }
+}
+
+impl<P> Drop for Checkout<'_, P> {
+    fn drop(&mut self) {
+        if let Some(client) = self.client.take() {
+            self.pool.checkin(client);
+        }
+    }
+}
+
+impl From<Client> for ClientPool {
+    fn from(c: Client) -> Self {
+        ClientPool::new(vec![c])
+    }
+}
+
+/// The coordinates of a place, how they were found, and its name if it was
+/// read.
+type Found = (LatLng, Resolution, Option<String>);
+
+/// Get the coordinates for each url, spreading the lookups across the clients
+/// in the pool. Results are yielded as they're found, with the index of their
+/// url and how they were found, along with the place's name if `fetch_names`
+/// is set for that url.
+pub(crate) fn coords_stream<'a, P: Page>(
+    pool: &'a ClientPool<P>,
+    urls: &'a [String],
+    fetch_names: &'a [bool],
+    lookup: &'a LookupOptions,
+) -> impl Stream<Item = (usize, Result<Found>)> + 'a {
+    let fetch_name = |i: usize| fetch_names.get(i) == Some(&true);
+    // those that don't need the browser first, so they aren't held up by it
+    let (fast, slow): (Vec<_>, Vec<_>) = urls
+        .iter()
+        .enumerate()
+        .partition(|&(i, url)| !needs_browser(url, fetch_name(i), lookup));
+    stream::iter(fast.into_iter().chain(slow))
+        .map(move |(i, url)| async move {
+            if !fetch_name(i) {
+                if let Some(result) = coords_without_browser(url, lookup) {
+                    let found = result
+                        .map(|(coords, resolution)| (coords, resolution, None));
+                    return (i, found);
+                }
+            }
+            let c = pool.checkout();
+            let result = match get_coords_for_url(&*c, url, lookup).await {
+                Ok((coords, resolution)) if fetch_name(i) => {
+                    let name = match place_name(&*c, url, coords, lookup).await
+                    {
+                        Ok(name) => Some(name),
+                        Err(e) => {
+                            warn!(
+                                "Failed to read the name of {url} with error \
+                                {e:#}. Continuing."
+                            );
+                            None
+                        }
+                    };
+                    Ok((coords, resolution, name))
+                }
+                result => result
+                    .map(|(coords, resolution)| (coords, resolution, None)),
+            };
+            if let Some(endpoint) = c.endpoint() {
+                debug!(url, endpoint, "Looked up with the WebDriver server");
+            }
+            (i, result)
+        })
+        .buffer_unordered(pool.len().max(1))
+}
+
+/// Whether looking up the url needs the browser, either to find the
+/// coordinates or to read the place's name.
+pub(crate) fn needs_browser(
+    url: &str,
+    fetch_name: bool,
+    lookup: &LookupOptions,
+) -> bool {
+    let cached = lookup.cached(url).is_some();
+    fetch_name || (!cached && coords_in_url(url, lookup).is_none())
+}
+
+/// Get the coordinates of the place at the url without the browser, from the
+/// cache or the url itself, and how they were found, if they can be.
+fn coords_without_browser(
+    url: &str,
+    lookup: &LookupOptions,
+) -> Option<Result<(LatLng, Resolution)>> {
+    let start = Instant::now();
+    if let Some(coords) = lookup.cached(url) {
+        if let Some(report) = &lookup.report {
+            report.record(url, start.elapsed(), LookupMethod::Cache);
+        }
+        let result = check_bounds(url, coords, lookup)
+            .map(|coords| (coords, Resolution::Cache));
+        return Some(result);
+    }
+    let (coords, resolution) = coords_in_url(url, lookup)?;
+    Some(found_coords(url, coords, resolution, start, lookup))
+}
+
+/// The coordinates in the url as given, read without going to it, and how
+/// they were found. Short links never have any.
+fn coords_in_url(
+    url: &str,
+    lookup: &LookupOptions,
+) -> Option<(LatLng, Resolution)> {
+    if is_short_link(url) {
+        return None;
+    }
+    let provider = lookup.provider.for_url(url);
+    lookup
+        .extractors
+        .iter()
+        .filter(|e| e.provider() == provider && !e.after_redirect())
+        .find_map(|e| Some((e.try_extract(url)?, Resolution::Url(e.name()))))
+}
+
+/// Read the name of the place at the url from its page, going to the url
+/// first unless the page is already centered on the place's coordinates.
+async fn place_name(
+    c: &impl Page,
+    url: &str,
+    coords: LatLng,
+    lookup: &LookupOptions,
+) -> Result<String> {
+    let current_url = c.current_url().await?;
+    let center =
+        MapCenterExtractor::default().try_extract(current_url.as_str());
+    if center != Some(coords) {
+        goto(c, url, lookup).await?;
+    }
+    let name = c
+        .wait_for_text(
+            Selector::Css("h1"),
+            lookup.poll_interval,
+            lookup.timeout,
+        )
+        .await?;
+    if name.trim().is_empty() {
+        return Err(GmapsError::NoName(url.to_string()));
+    }
+    Ok(name.trim().to_string())
+}
+
+/// Look up the coordinates of the place at a Google Maps url with the default
+/// [`LookupOptions`], retrying WebDriver errors.
+///
+/// The page is only navigated, so the caller owns it: for a client, connecting
+/// it before, and closing it once it's no longer needed.
+pub async fn resolve_url(client: &impl Page, url: &str) -> Result<LatLng> {
+    let (coords, _) =
+        get_coords_for_url(client, url, &LookupOptions::default()).await?;
+    Ok(coords)
+}
+
+/// Get the coordinates of the place at the url, without the browser if
+/// possible, and how they were found.
+#[instrument(skip(c, lookup))]
+pub(crate) async fn get_coords_for_url(
+    c: &impl Page,
+    url: &str,
+    lookup: &LookupOptions,
+) -> Result<(LatLng, Resolution)> {
+    if let Some(result) = coords_without_browser(url, lookup) {
+        return result;
+    }
+    let start = Instant::now();
+    let mut attempt = 0;
+    // how many times longer than usual to wait for the page
+    let mut timeout_factor = 1;
+    let (coords, resolution) = loop {
+        match find_coords_for_url(c, url, lookup, timeout_factor).await {
+            Ok(found) => {
+                if timeout_factor > 1 {
+                    info!(
+                        "Found coordinates on retrying with a longer timeout"
+                    );
+                }
+                break found;
+            }
+            Err(e @ GmapsError::Timeout { .. })
+                if lookup.retry_timeout && timeout_factor == 1 =>
+            {
+                warn!("{e}. Retrying with double the timeout.");
+                timeout_factor = 2;
+            }
+            // WebDriver errors may be transient, unlike not finding coordinates
+            Err(e @ GmapsError::WebDriver(_))
+                if attempt < lookup.max_retries =>
+            {
+                let backoff = RETRY_BACKOFF * 2u32.pow(attempt);
+                attempt += 1;
+                warn!(
+                    "WebDriver error {e}. Retrying in {} seconds ({attempt} of \
+                    {} retries).",
+                    backoff.as_secs_f64(),
+                    lookup.max_retries
+                );
+                sleep(backoff).await;
+            }
+            Err(e) if attempt > 0 => {
+                return Err(GmapsError::RetriesExhausted {
+                    attempts: attempt + 1,
+                    error: Box::new(e),
+                })
+            }
+            Err(e) => return Err(e),
+        }
+    };
+    found_coords(url, coords, resolution, start, lookup)
+}
+
+/// Check the coordinates found for the url since the start of the lookup are
+/// in the expected region, then cache and report them.
+fn found_coords(
+    url: &str,
+    coords: LatLng,
+    resolution: Resolution,
+    start: Instant,
+    lookup: &LookupOptions,
+) -> Result<(LatLng, Resolution)> {
+    // likely the wrong place, so it isn't cached either
+    let coords = check_bounds(url, coords, lookup)?;
+    if let Some(cache) = &lookup.cache {
+        if let Err(e) = cache.insert(url, coords) {
+            warn!("Failed to update cache with error {e}. Continuing.");
+        }
+    }
+    if let Some(report) = &lookup.report {
+        report.record(url, start.elapsed(), resolution.method());
+    }
+    Ok((coords, resolution))
+}
+
+/// The coordinates, or an error if they're outside the expected region.
+fn check_bounds(
+    url: &str,
+    coords: LatLng,
+    lookup: &LookupOptions,
+) -> Result<LatLng> {
+    match lookup.bounds {
+        Some(bounds) if !bounds.contains(coords) => {
+            Err(GmapsError::OutOfBounds {
+                url: url.to_string(),
+                lat: coords.lat,
+                lng: coords.lng,
+            })
+        }
+        _ => Ok(coords),
+    }
+}
+
+/// Go to the url and get the coordinates of the place, and how they were found.
+async fn find_coords_for_url(
+    c: &impl Page,
+    url: &str,
+    lookup: &LookupOptions,
+    timeout_factor: u32,
+) -> Result<(LatLng, Resolution)> {
+    // short links don't contain any coordinates, so expand them first
+    let expanded = match is_short_link(url) {
+        true => Some(expand_short_link(c, url, lookup).await?),
+        false => None,
+    };
+    let url = expanded.as_deref().unwrap_or(url);
+
+    if let Some(found) = coords_in_url(url, lookup) {
+        return Ok(found);
+    }
+    let provider = lookup.provider.for_url(url);
+    // only Google's pages are known to update with the place's coordinates
+    if provider != Provider::Google {
+        return Err(GmapsError::NoCoordinates(url.to_string()));
+    }
+
+    // read once the page redirects, e.g. when it updates with the view center
+    let extract = |page_url: &str| {
+        lookup
+            .extractors
+            .iter()
+            .filter(|e| e.provider() == provider && e.after_redirect())
+            .find_map(|e| Some((e.try_extract(page_url)?, e.name())))
+    };
+    if expanded.is_none() {
+        goto(c, url, lookup).await?;
+    }
+    // places only identified by id need the page to fully load to center
+    let timeout = match PLACE_ID_RE.is_match(url) {
+        true => lookup.place_id_timeout,
+        false => lookup.timeout,
+    } * timeout_factor;
+    let start = Instant::now();
+    // the coordinates of the last poll, when waiting for them to settle
+    let mut last = None;
+    if lookup.wait_strategy == WaitStrategy::Element {
+        let canvas = c
+            .wait_for_text(
+                Selector::Css(MAP_CANVAS_SELECTOR),
+                lookup.poll_interval,
+                timeout,
+            )
+            .await;
+        if canvas.is_ok() {
+            let redirected_url = c.current_url().await?;
+            // a search's url is centered on its results, not a place
+            let redirected = (expanded.is_some()
+                || redirected_url.as_str() != url)
+                && !is_search_url(&redirected_url);
+            if let (true, Some((coords, name))) =
+                (redirected, extract(redirected_url.as_str()))
+            {
+                if !lookup.wait_settled {
+                    let seconds = start.elapsed().as_secs_f64();
+                    info!(seconds, "Fetched coordinates in {seconds} seconds");
+                    return Ok((coords, Resolution::Browser(name)));
+                }
+                last = Some((coords, name));
+            }
+        }
+        debug!("Map not centered on the place yet, polling the page url");
+    }
+    let polls = timeout.saturating_sub(start.elapsed()).as_millis()
+        / lookup.poll_interval.as_millis().max(1);
+    let mut searching = false;
+    for _ in 0..polls {
+        sleep(lookup.poll_interval).await;
+        let redirected_url = c.current_url().await?;
+        debug!(url = redirected_url.as_str(), "Polled page url");
+        if check_blocked(c, &redirected_url, lookup).await? {
+            continue;
+        }
+        // a search's url is centered on its results, not a place, until it
+        // redirects to the place if there's only one
+        searching = is_search_url(&redirected_url);
+        if searching {
+            if let Some(coords) = first_result(c, url, lookup).await? {
+                return Ok((coords, Resolution::Browser("first-result")));
+            }
+            continue;
+        }
+        // an expanded short link has already been redirected
+        if expanded.is_some() || redirected_url.as_str() != url {
+            if let Some((coords, name)) = extract(redirected_url.as_str()) {
+                if lookup.wait_settled && last != Some((coords, name)) {
+                    debug!("Waiting for the coordinates to settle");
+                    last = Some((coords, name));
+                    continue;
+                }
+                let seconds = start.elapsed().as_secs_f64();
+                info!(seconds, "Fetched coordinates in {seconds} seconds");
+                return Ok((coords, Resolution::Browser(name)));
+            }
+        }
+    }
+    // better than nothing, if the map never stopped moving
+    if let Some((coords, name)) = last {
+        warn!("Coordinates of {url} didn't settle before the timeout");
+        return Ok((coords, Resolution::Browser(name)));
+    }
+    if searching {
+        return Err(GmapsError::AmbiguousPlace(url.to_string()));
+    }
+    Err(GmapsError::Timeout {
+        url: url.to_string(),
+        secs: timeout.as_secs_f64(),
+    })
+}
+
+/// Whether the page is a search, which lists the results if there's more than
+/// one.
+fn is_search_url(page_url: &Url) -> bool {
+    page_url.path().contains("/maps/search/")
+}
+
+/// If the page lists several search results, the coordinates of the first
+/// result when allowed to use it, or an error otherwise. None if no results
+/// are listed, e.g. while the page loads.
+async fn first_result(
+    c: &impl Page,
+    url: &str,
+    lookup: &LookupOptions,
+) -> Result<Option<LatLng>> {
+    if !c.has_element(Selector::Css(RESULTS_LIST_SELECTOR)).await {
+        return Ok(None);
+    }
+    if !lookup.first_result {
+        return Err(GmapsError::AmbiguousPlace(url.to_string()));
+    }
+    let link = format!("{RESULTS_LIST_SELECTOR} {RESULT_LINK_SELECTOR}");
+    if !c.has_element(Selector::Css(&link)).await {
+        return Ok(None);
+    }
+    let href = c.attr(Selector::Css(&link), "href").await?;
+    let extractor = DataParamExtractor::default();
+    match href.and_then(|href| extractor.try_extract(&href)) {
+        Some(coords) => {
+            info!("Using the first of several search results");
+            Ok(Some(coords))
+        }
+        _ => Err(GmapsError::AmbiguousPlace(url.to_string())),
+    }
+}
+
+/// Navigate to the url, once the rate limit allows it. The tab is cleared
+/// first, so that the url it's left at by the previous place, centered on
+/// that place, can't be mistaken for this one's while it loads.
+async fn goto(
+    c: &impl Page,
+    url: &str,
+    lookup: &LookupOptions,
+) -> Result<(), WebDriverError> {
+    c.goto("about:blank").await?;
+    lookup.rate_limiter.wait().await;
+    c.goto(url).await
+}
+
+/// Check whether Google is showing a page that stops the place from loading,
+/// and return an error if so. If allowed to, accept a consent page and return
+/// true, since the place should load once it's accepted.
+async fn check_blocked(
+    c: &impl Page,
+    page_url: &Url,
+    lookup: &LookupOptions,
+) -> Result<bool> {
+    let host = page_url.host_str().unwrap_or_default();
+    if host == CONSENT_HOST {
+        if lookup.accept_consent
+            && c.click(Selector::XPath(CONSENT_ACCEPT_XPATH)).await?
+        {
+            info!("Accepted Google consent page");
+            return Ok(true);
+        }
+        return Err(BlockedError::ConsentRequired(page_url.to_string()).into());
+    }
+    if host.contains("google.") && page_url.path().starts_with("/sorry") {
+        return Err(
+            BlockedError::CaptchaEncountered(page_url.to_string()).into()
+        );
+    }
+    Ok(false)
+}
+
+/// Go to a short link and return the full url it redirects to.
+async fn expand_short_link(
+    c: &impl Page,
+    url: &str,
+    lookup: &LookupOptions,
+) -> Result<String> {
+    goto(c, url, lookup).await?;
+    let polls =
+        lookup.timeout.as_millis() / lookup.poll_interval.as_millis().max(1);
+    for _ in 0..polls {
+        let redirected_url = c.current_url().await?;
+        if !check_blocked(c, &redirected_url, lookup).await?
+            && !is_short_link(redirected_url.as_str())
+        {
+            return Ok(redirected_url.into());
+        }
+        sleep(lookup.poll_interval).await;
+    }
+    Err(GmapsError::Timeout {
+        url: url.to_string(),
+        secs: lookup.timeout.as_secs_f64(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::{json, Value};
+
+    use super::*;
+    use crate::page::fake::FakePage;
+
+    fn capabilities(
+        browser: Browser,
+        headless: bool,
+        user_agent: Option<&str>,
+        proxy: Option<&str>,
+    ) -> Value {
+        let proxy = proxy.map(|proxy| Url::parse(proxy).unwrap());
+        Value::Object(browser.capabilities(
+            headless,
+            user_agent,
+            proxy.as_ref(),
+        ))
+    }
+
+    #[test]
+    fn headless_capabilities() {
+        assert_eq!(
+            capabilities(Browser::Firefox, true, None, None),
+            json!({
+                "browserName": "firefox",
+                "moz:firefoxOptions": {"args": ["--headless"]}
+            })
+        );
+        assert_eq!(
+            capabilities(Browser::Chrome, true, None, None),
+            json!({
+                "browserName": "chrome",
+                "goog:chromeOptions": {"args": ["--headless=new"]}
+            })
+        );
+    }
+
+    #[test]
+    fn noheadless_capabilities_have_no_options() {
+        assert_eq!(
+            capabilities(Browser::Firefox, false, None, None),
+            json!({"browserName": "firefox"})
+        );
+        assert_eq!(
+            capabilities(Browser::Chrome, false, None, None),
+            json!({"browserName": "chrome"})
+        );
+    }
+
+    #[test]
+    fn user_agent_capabilities() {
+        assert_eq!(
+            capabilities(Browser::Firefox, true, Some("Agent/1.0"), None),
+            json!({
+                "browserName": "firefox",
+                "moz:firefoxOptions": {
+                    "args": ["--headless"],
+                    "prefs": {"general.useragent.override": "Agent/1.0"}
+                }
+            })
+        );
+        assert_eq!(
+            capabilities(Browser::Chrome, false, Some("Agent/1.0"), None),
+            json!({
+                "browserName": "chrome",
+                "goog:chromeOptions": {"args": ["--user-agent=Agent/1.0"]}
+            })
+        );
+    }
+
+    #[test]
+    fn http_proxy_capabilities() {
+        let proxy = Some("http://proxy.internal:3128");
+        assert_eq!(
+            capabilities(Browser::Firefox, false, None, proxy),
+            json!({
+                "browserName": "firefox",
+                "moz:firefoxOptions": {
+                    "prefs": {
+                        "network.proxy.type": 1,
+                        "network.proxy.http": "proxy.internal",
+                        "network.proxy.http_port": 3128,
+                        "network.proxy.ssl": "proxy.internal",
+                        "network.proxy.ssl_port": 3128
+                    }
+                }
+            })
+        );
+        assert_eq!(
+            capabilities(Browser::Chrome, true, None, proxy),
+            json!({
+                "browserName": "chrome",
+                "goog:chromeOptions": {
+                    "args": [
+                        "--headless=new",
+                        "--proxy-server=http://proxy.internal:3128"
+                    ]
+                }
+            })
+        );
+    }
+
+    #[test]
+    fn socks_proxy_capabilities() {
+        assert_eq!(
+            capabilities(
+                Browser::Firefox,
+                false,
+                None,
+                Some("socks4://p:1080")
+            ),
+            json!({
+                "browserName": "firefox",
+                "moz:firefoxOptions": {
+                    "prefs": {
+                        "network.proxy.type": 1,
+                        "network.proxy.socks": "p",
+                        "network.proxy.socks_port": 1080,
+                        "network.proxy.socks_version": 4,
+                        "network.proxy.socks_remote_dns": true
+                    }
+                }
+            })
+        );
+        assert_eq!(
+            capabilities(Browser::Chrome, false, None, Some("socks://p:1080")),
+            json!({
+                "browserName": "chrome",
+                "goog:chromeOptions": {
+                    "args": ["--proxy-server=socks5://p:1080"]
+                }
+            })
+        );
+    }
+
+    const PLACE: &str = "https://www.google.com/maps/place/Cafe";
+
+    fn quick_lookup() -> LookupOptions {
+        LookupOptions {
+            timeout: Duration::from_millis(50),
+            poll_interval: Duration::from_millis(5),
+            ..LookupOptions::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn coords_found_once_the_page_redirects() {
+        let page = FakePage::default()
+            .redirect(PLACE, &[PLACE, &format!("{PLACE}/@1.5,2.5,17z")]);
+        let (coords, resolution) =
+            get_coords_for_url(&page, PLACE, &quick_lookup())
+                .await
+                .unwrap();
+        assert_eq!((coords.lat, coords.lng), (1.5, 2.5));
+        assert_eq!(resolution, Resolution::Browser("redirect"));
+        assert_eq!(*page.visited.lock().unwrap(), ["about:blank", PLACE]);
+    }
+
+    #[tokio::test]
+    async fn coords_found_once_they_settle() {
+        let page = FakePage::default().redirect(
+            PLACE,
+            &[
+                &format!("{PLACE}/@1,2,14z"),
+                &format!("{PLACE}/@1.5,2.5,17z"),
+            ],
+        );
+        let lookup = LookupOptions {
+            wait_settled: true,
+            ..quick_lookup()
+        };
+        let (coords, _) =
+            get_coords_for_url(&page, PLACE, &lookup).await.unwrap();
+        assert_eq!((coords.lat, coords.lng), (1.5, 2.5));
+    }
+
+    #[tokio::test]
+    async fn timeout_if_the_page_never_redirects() {
+        let page = FakePage::default();
+        let result = get_coords_for_url(&page, PLACE, &quick_lookup()).await;
+        match result {
+            Err(GmapsError::Timeout { url, .. }) => assert_eq!(url, PLACE),
+            result => panic!("expected a timeout, got {result:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn ambiguous_place_if_the_search_has_several_results() {
+        let search = "https://www.google.com/maps/search/cafe/@1,2,14z";
+        let page = FakePage::default()
+            .redirect(PLACE, &[search])
+            .element(RESULTS_LIST_SELECTOR, "");
+        let result = get_coords_for_url(&page, PLACE, &quick_lookup()).await;
+        match result {
+            Err(GmapsError::AmbiguousPlace(url)) => assert_eq!(url, PLACE),
+            result => panic!("expected an ambiguous place, got {result:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn first_result_of_the_search() {
+        let search = "https://www.google.com/maps/search/cafe/@1,2,14z";
+        let link = format!("{RESULTS_LIST_SELECTOR} {RESULT_LINK_SELECTOR}");
+        let page = FakePage::default()
+            .redirect(PLACE, &[search])
+            .element(RESULTS_LIST_SELECTOR, "")
+            .element(
+                &link,
+                "https://www.google.com/maps/place/A/data=!3d3.5!4d4.5",
+            );
+        let lookup = LookupOptions {
+            first_result: true,
+            ..quick_lookup()
+        };
+        let (coords, resolution) =
+            get_coords_for_url(&page, PLACE, &lookup).await.unwrap();
+        assert_eq!((coords.lat, coords.lng), (3.5, 4.5));
+        assert_eq!(resolution, Resolution::Browser("first-result"));
+    }
+
+    #[tokio::test]
+    async fn consent_required_unless_accepted() {
+        let consent = "https://consent.google.com/ml?continue=maps";
+        let page = FakePage::default()
+            .redirect(PLACE, &[consent, &format!("{PLACE}/@1.5,2.5,17z")])
+            .element(CONSENT_ACCEPT_XPATH, "Accept all");
+        let result = get_coords_for_url(&page, PLACE, &quick_lookup()).await;
+        assert!(
+            matches!(
+                result,
+                Err(GmapsError::Blocked(BlockedError::ConsentRequired(_)))
+            ),
+            "{result:?}"
+        );
+
+        let lookup = LookupOptions {
+            accept_consent: true,
+            ..quick_lookup()
+        };
+        let (coords, _) =
+            get_coords_for_url(&page, PLACE, &lookup).await.unwrap();
+        assert_eq!((coords.lat, coords.lng), (1.5, 2.5));
+    }
+
+    #[tokio::test]
+    async fn back_to_back_lookups_clear_the_tab_between_places() {
+        let other = "https://www.google.com/maps/place/Bakery";
+        let page = FakePage::default()
+            .redirect(PLACE, &[PLACE, &format!("{PLACE}/@1.5,2.5,17z")])
+            .redirect(other, &[other, &format!("{other}/@3,4,17z")]);
+        let lookup = quick_lookup();
+        let (coords, _) =
+            get_coords_for_url(&page, PLACE, &lookup).await.unwrap();
+        assert_eq!((coords.lat, coords.lng), (1.5, 2.5));
+        let (coords, _) =
+            get_coords_for_url(&page, other, &lookup).await.unwrap();
+        assert_eq!((coords.lat, coords.lng), (3.0, 4.0));
+        assert_eq!(
+            *page.visited.lock().unwrap(),
+            ["about:blank", PLACE, "about:blank", other]
+        );
+    }
+
+    #[tokio::test]
+    async fn same_place_again_by_another_url() {
+        let other = "https://www.google.com/maps/place/Cafe+Again";
+        let place = format!("{PLACE}/@1.5,2.5,17z");
+        // both land straight on the place's url the last lookup ended at
+        let page = FakePage::default()
+            .redirect(PLACE, &[&place])
+            .redirect(other, &[&place]);
+        let lookup = quick_lookup();
+        for url in [PLACE, other, other] {
+            let (coords, _) =
+                get_coords_for_url(&page, url, &lookup).await.unwrap();
+            assert_eq!((coords.lat, coords.lng), (1.5, 2.5));
+        }
+    }
+
+    #[tokio::test]
+    async fn force_refetch_bypasses_the_cache() {
+        let url = "https://www.google.com/maps/place/A/data=!3d1.5!4d2.5";
+        let place = "https://www.google.com/maps/place/B";
+        let path = std::env::temp_dir()
+            .join(format!("gmaps-coords-{}-cache.json", std::process::id()));
+        let cache = CoordCache::load(path.clone()).unwrap();
+        let stale = LatLng {
+            lat: 9.0,
+            lng: 9.0,
+            alt: None,
+        };
+        cache.insert(url, stale).unwrap();
+        cache.insert(place, stale).unwrap();
+        let mut lookup = LookupOptions {
+            cache: Some(cache),
+            ..quick_lookup()
+        };
+        let page = FakePage::default();
+        let (coords, resolution) =
+            get_coords_for_url(&page, url, &lookup).await.unwrap();
+        assert_eq!((coords, resolution), (stale, Resolution::Cache));
+        assert!(!needs_browser(place, false, &lookup));
+
+        lookup.force_refetch = true;
+        let (coords, resolution) =
+            get_coords_for_url(&page, url, &lookup).await.unwrap();
+        assert_eq!((coords.lat, coords.lng), (1.5, 2.5));
+        assert_eq!(resolution, Resolution::Url("data-param"));
+        assert!(needs_browser(place, false, &lookup));
+        // the fresh coordinates replace the cached ones
+        let cached = lookup.cache.as_ref().unwrap().get(url).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!((cached.lat, cached.lng), (1.5, 2.5));
+    }
+}
@@ -0,0 +1,467 @@
+//! Reading coordinates straight from map urls.
+
+use std::{fmt, sync::LazyLock};
+
+use clap::ValueEnum;
+use regex::Regex;
+use tracing::warn;
+use url::Url;
+
+use crate::{GmapsError, LatLng, Result};
+
+// A latitude,longitude regex pattern. E.g. "-25.0,160.0".
+// (?:) denotes a non-capturing group. ()? denotes an optional group.
+const LATLNGPAT: &str = r"(-?\d+(?:\.\d+)?),(-?\d+(?:\.\d+)?)";
+
+// An optional altitude in meters following a latitude,longitude pattern, as
+// in Google Earth style urls. E.g. ",250a".
+const ALTPAT: &str = r"(?:,(-?\d+(?:\.\d+)?)a)?";
+
+// The place's coordinates embedded in a url's data parameter, as latitude then
+// longitude. E.g. "!3d-33.8568!4d151.2153".
+const DATAPAT: &str = r"!3d(-?\d+\.?\d*)!4d(-?\d+\.?\d*)";
+
+// The url query parameters that can hold a latitude,longitude pattern. E.g.
+// "?q=", "&query=", "?ll=" or "&center=".
+const COORD_PARAMS_PAT: &str = r"[?&](?:q|query|ll|center)=";
+
+// A place id token identifying the place without its coordinates, either as
+// an ftid parameter or in the data parameter. E.g. "ftid=0x6b12ae4:0x17d2d5a"
+// or "!1s0x6b12ae4:0x17d2d5a".
+const PLACE_ID_PAT: &str = r"(?:ftid=|!1s)0x[0-9a-fA-F]+:0x[0-9a-fA-F]+";
+
+// A number in an OpenStreetMap url, latitude or longitude.
+const OSM_NUMBER_PAT: &str = r"(-?\d+(?:\.\d+)?)";
+
+// The patterns compiled once, and shared by every lookup.
+static DATA_PARAM_RE: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(DATAPAT).unwrap());
+static COORD_PARAM_RE: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(&format!("{COORD_PARAMS_PAT}{LATLNGPAT}")).unwrap()
+});
+static MAP_CENTER_RE: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(&format!("@{LATLNGPAT}{ALTPAT}")).unwrap());
+static APPLE_RE: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(&format!("[?&](?:ll|coordinate|sll)={LATLNGPAT}")).unwrap()
+});
+static OSM_MARKER_RE: LazyLock<Regex> = LazyLock::new(|| {
+    let number = OSM_NUMBER_PAT;
+    Regex::new(&format!("[?&]mlat={number}&mlon={number}")).unwrap()
+});
+static OSM_CENTER_RE: LazyLock<Regex> = LazyLock::new(|| {
+    let number = OSM_NUMBER_PAT;
+    Regex::new(&format!(r"#map=\d+/{number}/{number}")).unwrap()
+});
+pub(crate) static PLACE_ID_RE: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(PLACE_ID_PAT).unwrap());
+
+// Hosts serving shortened Google Maps links, which redirect to the full url.
+const SHORT_LINK_HOSTS: [&str; 2] = ["goo.gl", "maps.app.goo.gl"];
+
+/// A map provider whose urls coordinates are read from.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum Provider {
+    /// Tell from each url's host, defaulting to Google
+    #[default]
+    Auto,
+    /// Google Maps
+    Google,
+    /// Apple Maps, e.g. "?ll=<lat>,<lng>"
+    Apple,
+    /// OpenStreetMap, e.g. "#map=<zoom>/<lat>/<lng>"
+    Osm,
+}
+
+impl Provider {
+    /// The provider of the url, telling from its host if automatic.
+    pub fn for_url(self, url: &str) -> Provider {
+        if self != Provider::Auto {
+            return self;
+        }
+        let host = Url::parse(url)
+            .ok()
+            .and_then(|u| u.host_str().map(str::to_string))
+            .unwrap_or_default();
+        if host == "maps.apple.com" {
+            Provider::Apple
+        } else if host == "openstreetmap.org"
+            || host.ends_with(".openstreetmap.org")
+        {
+            Provider::Osm
+        } else {
+            Provider::Google
+        }
+    }
+}
+
+// Query parameters that only track where a link was shared from, rather than
+// saying which place it's of. Parameters starting with "utm_" are too.
+const TRACKING_PARAMS: [&str; 9] = [
+    "entry", "g_ep", "g_st", "shorturl", "coh", "skid", "ved", "ei", "usg",
+];
+
+/// The url without its tracking parameters, e.g. "entry=ttu". The rest of it
+/// is kept as is, since re-encoding the query would e.g. escape the comma in
+/// "q=<lat>,<lng>".
+pub(crate) fn clean_url(url: &str) -> String {
+    let (rest, fragment) = match url.split_once('#') {
+        Some((rest, fragment)) => (rest, Some(fragment)),
+        None => (url, None),
+    };
+    let Some((base, query)) = rest.split_once('?') else {
+        return url.to_string();
+    };
+    let kept = query
+        .split('&')
+        .filter(|pair| {
+            let key = pair.split('=').next().unwrap_or_default();
+            !key.is_empty()
+                && !key.starts_with("utm_")
+                && !TRACKING_PARAMS.contains(&key)
+        })
+        .collect::<Vec<_>>();
+    let mut cleaned = base.to_string();
+    if !kept.is_empty() {
+        cleaned.push('?');
+        cleaned.push_str(&kept.join("&"));
+    }
+    if let Some(fragment) = fragment {
+        cleaned.push('#');
+        cleaned.push_str(fragment);
+    }
+    cleaned
+}
+
+/// Whether the url is a shortened Google Maps link.
+pub(crate) fn is_short_link(url: &str) -> bool {
+    Url::parse(url)
+        .ok()
+        .and_then(|u| u.host_str().map(|h| SHORT_LINK_HOSTS.contains(&h)))
+        .unwrap_or(false)
+}
+
+/// Parse the coordinates contained in text, according to the given regex.
+///
+/// The regex captures the latitude, the longitude, and optionally the
+/// altitude, in that order. Coordinates outside of the valid latitude and
+/// longitude ranges are an error.
+fn coords_from_regex(pattern: &Regex, text: &str) -> Result<LatLng> {
+    if let Some(captures) = pattern.captures(text) {
+        let lat = captures[1].parse::<f64>()?;
+        let lng = captures[2].parse::<f64>()?;
+        if !(-90.0..=90.0).contains(&lat) || !(-180.0..=180.0).contains(&lng) {
+            warn!("Invalid coordinates {lat},{lng} found in {text}");
+            return Err(GmapsError::InvalidCoordinates { lat, lng });
+        }
+        let alt = match captures.get(3) {
+            Some(alt) => Some(alt.as_str().parse::<f64>()?),
+            None => None,
+        };
+        Ok(LatLng { lat, lng, alt })
+    } else {
+        Err(GmapsError::NoCoordinates(text.to_string()))
+    }
+}
+
+/// A way of reading a place's coordinates from a url.
+pub trait CoordExtractor: fmt::Debug + Send + Sync {
+    /// The coordinates of the place in the url, if this finds any.
+    fn try_extract(&self, url: &str) -> Option<LatLng>;
+
+    /// The map provider whose urls this reads.
+    fn provider(&self) -> Provider {
+        Provider::Google
+    }
+
+    /// Whether this reads the url the page redirects to, rather than the url
+    /// as given, which is read before going to it.
+    fn after_redirect(&self) -> bool {
+        false
+    }
+
+    /// A short name for how this reads coordinates, which places found by it
+    /// are annotated with if asked to.
+    fn name(&self) -> &'static str {
+        "url"
+    }
+}
+
+/// Reads the coordinates in a url's data parameter, which are those of the
+/// place itself. E.g. "!3d-33.8568!4d151.2153".
+#[derive(Debug)]
+pub struct DataParamExtractor(Regex);
+
+impl Default for DataParamExtractor {
+    fn default() -> Self {
+        DataParamExtractor(DATA_PARAM_RE.clone())
+    }
+}
+
+impl CoordExtractor for DataParamExtractor {
+    fn try_extract(&self, url: &str) -> Option<LatLng> {
+        coords_from_regex(&self.0, url).ok()
+    }
+
+    fn name(&self) -> &'static str {
+        "data-param"
+    }
+}
+
+/// Reads a coordinate query, e.g. "?q=-33.8568,151.2153". The map isn't
+/// centered on these, so they have to be read from the url as given.
+#[derive(Debug)]
+pub struct CoordParamExtractor(Regex);
+
+impl Default for CoordParamExtractor {
+    fn default() -> Self {
+        CoordParamExtractor(COORD_PARAM_RE.clone())
+    }
+}
+
+impl CoordExtractor for CoordParamExtractor {
+    fn try_extract(&self, url: &str) -> Option<LatLng> {
+        coords_from_regex(&self.0, url).ok()
+    }
+
+    fn name(&self) -> &'static str {
+        "q-param"
+    }
+}
+
+/// Reads the view center, e.g. "@-33.8568,151.2153,17z", which is the place
+/// once the page has redirected to it. The center in its own path segment, as
+/// in "/maps/place/Cafe/@-33.8568,151.2153,17z/data=...", is preferred over
+/// any other "@" in the url, such as one inside the data parameter.
+#[derive(Debug)]
+pub struct MapCenterExtractor(Regex);
+
+impl Default for MapCenterExtractor {
+    fn default() -> Self {
+        MapCenterExtractor(MAP_CENTER_RE.clone())
+    }
+}
+
+impl CoordExtractor for MapCenterExtractor {
+    fn try_extract(&self, url: &str) -> Option<LatLng> {
+        let in_path = Url::parse(url).ok().and_then(|u| {
+            u.path_segments()?
+                .filter(|segment| segment.starts_with('@'))
+                .find_map(|segment| coords_from_regex(&self.0, segment).ok())
+        });
+        in_path.or_else(|| coords_from_regex(&self.0, url).ok())
+    }
+
+    fn after_redirect(&self) -> bool {
+        true
+    }
+
+    fn name(&self) -> &'static str {
+        "redirect"
+    }
+}
+
+/// Reads the coordinates of an Apple Maps url, e.g. "?ll=50.8949,4.3416".
+#[derive(Debug)]
+pub struct AppleExtractor(Regex);
+
+impl Default for AppleExtractor {
+    fn default() -> Self {
+        AppleExtractor(APPLE_RE.clone())
+    }
+}
+
+impl CoordExtractor for AppleExtractor {
+    fn try_extract(&self, url: &str) -> Option<LatLng> {
+        coords_from_regex(&self.0, url).ok()
+    }
+
+    fn provider(&self) -> Provider {
+        Provider::Apple
+    }
+
+    fn name(&self) -> &'static str {
+        "apple-param"
+    }
+}
+
+/// Reads the coordinates of an OpenStreetMap url, from its marker, e.g.
+/// "?mlat=51.5&mlon=-0.12", or else the view center, e.g. "#map=17/51.5/-0.12".
+#[derive(Debug)]
+pub struct OsmExtractor {
+    marker: Regex,
+    center: Regex,
+}
+
+impl Default for OsmExtractor {
+    fn default() -> Self {
+        OsmExtractor {
+            marker: OSM_MARKER_RE.clone(),
+            center: OSM_CENTER_RE.clone(),
+        }
+    }
+}
+
+impl CoordExtractor for OsmExtractor {
+    fn try_extract(&self, url: &str) -> Option<LatLng> {
+        coords_from_regex(&self.marker, url)
+            .or_else(|_| coords_from_regex(&self.center, url))
+            .ok()
+    }
+
+    fn provider(&self) -> Provider {
+        Provider::Osm
+    }
+
+    fn name(&self) -> &'static str {
+        "osm-param"
+    }
+}
+
+/// The extractors for every provider. Google's are the data parameter,
+/// coordinate query and view center extractors, in that order.
+pub fn default_extractors() -> Vec<Box<dyn CoordExtractor>> {
+    vec![
+        Box::new(DataParamExtractor::default()),
+        Box::new(CoordParamExtractor::default()),
+        Box::new(MapCenterExtractor::default()),
+        Box::new(AppleExtractor::default()),
+        Box::new(OsmExtractor::default()),
+    ]
+}
+
+/// Whether the url is a link to a Google Maps place.
+pub(crate) fn is_google_maps_url(url: &str) -> bool {
+    Url::parse(url)
+        .ok()
+        .and_then(|u| {
+            let host = u.host_str()?;
+            Some(
+                SHORT_LINK_HOSTS.contains(&host)
+                    || host.starts_with("maps.google.")
+                    || (host.contains("google.")
+                        && u.path().starts_with("/maps")),
+            )
+        })
+        .unwrap_or(false)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn latlng(lat: f64, lng: f64) -> LatLng {
+        LatLng {
+            lat,
+            lng,
+            alt: None,
+        }
+    }
+
+    #[test]
+    fn coord_param_is_read_as_lat_lng() {
+        let coords = coords_from_regex(
+            &COORD_PARAM_RE,
+            "https://maps.google.com/?q=-25.0,160.0",
+        )
+        .unwrap();
+        assert_eq!(coords, latlng(-25.0, 160.0));
+    }
+
+    #[test]
+    fn map_center_ignores_zoom() {
+        let coords = coords_from_regex(
+            &MAP_CENTER_RE,
+            "https://www.google.com/maps/place/NYC/@40.7,-74.0,15z",
+        )
+        .unwrap();
+        assert_eq!(coords, latlng(40.7, -74.0));
+    }
+
+    #[test]
+    fn negative_and_integer_coordinates() {
+        let coords = coords_from_regex(&COORD_PARAM_RE, "?q=-33,-70").unwrap();
+        assert_eq!(coords, latlng(-33.0, -70.0));
+        let coords = coords_from_regex(&MAP_CENTER_RE, "@12,34,10z").unwrap();
+        assert_eq!(coords, latlng(12.0, 34.0));
+    }
+
+    #[test]
+    fn text_without_coordinates_is_an_error() {
+        let result = coords_from_regex(
+            &COORD_PARAM_RE,
+            "https://www.google.com/maps/place/Cafe",
+        );
+        assert!(matches!(result, Err(GmapsError::NoCoordinates(_))));
+    }
+
+    #[test]
+    fn boundary_coordinates_are_valid() {
+        for (text, lat, lng) in [
+            ("?q=90,180", 90.0, 180.0),
+            ("?q=-90,-180", -90.0, -180.0),
+            ("?q=90.0,-180.0", 90.0, -180.0),
+        ] {
+            let coords = coords_from_regex(&COORD_PARAM_RE, text).unwrap();
+            assert_eq!(coords, latlng(lat, lng));
+        }
+    }
+
+    #[test]
+    fn out_of_range_coordinates_are_invalid() {
+        for text in ["?q=90.0001,0", "?q=-90.0001,0", "?q=0,180.0001"] {
+            let result = coords_from_regex(&COORD_PARAM_RE, text);
+            assert!(
+                matches!(result, Err(GmapsError::InvalidCoordinates { .. })),
+                "{text}"
+            );
+        }
+        assert!(DataParamExtractor::default()
+            .try_extract("!3d0!4d-180.0001")
+            .is_none());
+    }
+
+    #[test]
+    fn map_center_prefers_its_own_path_segment() {
+        let url =
+            "https://www.google.com/maps/place/Cafe/data=!4m2!3m1!1s@1.0,2.0\
+            /@-33.8,151.2,17z";
+        let coords = MapCenterExtractor::default().try_extract(url).unwrap();
+        assert_eq!(coords, latlng(-33.8, 151.2));
+    }
+
+    #[test]
+    fn map_center_falls_back_to_any_at() {
+        let url = "https://www.google.com/maps?x=@7.0,8.0";
+        let coords = MapCenterExtractor::default().try_extract(url).unwrap();
+        assert_eq!(coords, latlng(7.0, 8.0));
+    }
+
+    #[test]
+    fn clean_url_drops_tracking_parameters() {
+        let place = "https://www.google.com/maps/place/Cafe";
+        for (url, cleaned) in [
+            (format!("{place}?entry=ttu"), place.to_string()),
+            (
+                format!("{place}?utm_source=share&hl=en&utm_medium=copy"),
+                format!("{place}?hl=en"),
+            ),
+            (format!("{place}?=x&&hl=en&"), format!("{place}?hl=en")),
+            (
+                "https://maps.google.com/?q=1.5,2.5&g_st=ic&ved=x".to_string(),
+                "https://maps.google.com/?q=1.5,2.5".to_string(),
+            ),
+            (
+                format!("{place}?entry=ttu#section"),
+                format!("{place}#section"),
+            ),
+            (
+                format!("{place}?hl=en#utm_source=x"),
+                format!("{place}?hl=en#utm_source=x"),
+            ),
+            (format!("{place}#entry=ttu"), format!("{place}#entry=ttu")),
+            (place.to_string(), place.to_string()),
+        ] {
+            assert_eq!(clean_url(&url), cleaned, "{url}");
+        }
+    }
+}
@@ -0,0 +1,94 @@
+//! Reading and writing files, stdin and stdout, compressed or not.
+
+use std::{
+    fs,
+    io::{self, Read},
+    path::Path,
+};
+
+use flate2::read::MultiGzDecoder;
+
+/// Whether the path is "-", meaning stdin for the input or stdout for the
+/// output.
+pub(crate) fn is_stdio(path: &Path) -> bool {
+    path == Path::new("-")
+}
+
+/// Open the input file for reading, or stdin if the path is "-". Files with a
+/// ".gz" extension are decompressed.
+pub(crate) fn open_input(path: &Path) -> io::Result<Box<dyn Read>> {
+    match is_stdio(path) {
+        true => Ok(Box::new(io::stdin())),
+        false if is_gzip(path) => {
+            Ok(Box::new(MultiGzDecoder::new(fs::File::open(path)?)))
+        }
+        false => Ok(Box::new(fs::File::open(path)?)),
+    }
+}
+
+/// Whether the path is of a gzip compressed file.
+pub(crate) fn is_gzip(path: &Path) -> bool {
+    path.extension().is_some_and(|e| e == "gz")
+}
+
+/// The extension of the path that tells its format, ignoring a ".gz"
+/// extension, e.g. "csv" for "places.csv.gz".
+pub(crate) fn format_extension(path: &Path) -> Option<&str> {
+    let path = match is_gzip(path) {
+        true => Path::new(path.file_stem()?),
+        false => path,
+    };
+    path.extension()?.to_str()
+}
+
+/// Read the whole input file, or stdin if the path is "-".
+pub(crate) fn read_input_to_string(path: &Path) -> io::Result<String> {
+    let mut s = String::new();
+    open_input(path)?.read_to_string(&mut s)?;
+    Ok(s)
+}
+
+/// Write the contents to a sibling temporary file, then move it into place,
+/// so the file is never left partially written.
+pub(crate) fn write_atomically(
+    path: &Path,
+    contents: impl AsRef<[u8]>,
+) -> io::Result<()> {
+    let mut tmp = path.to_path_buf().into_os_string();
+    tmp.push(".tmp");
+    fs::write(&tmp, contents)?;
+    fs::rename(&tmp, path)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use flate2::{write::GzEncoder, Compression};
+
+    use super::*;
+
+    #[test]
+    fn format_extension_ignores_gzip() {
+        assert_eq!(format_extension(Path::new("places.csv")), Some("csv"));
+        assert_eq!(format_extension(Path::new("places.csv.gz")), Some("csv"));
+        assert_eq!(format_extension(Path::new("places.gz")), None);
+        assert_eq!(format_extension(Path::new("places")), None);
+        assert!(is_gzip(Path::new("places.json.gz")));
+        assert!(!is_gzip(Path::new("places.json")));
+        assert!(is_stdio(Path::new("-")));
+        assert!(!is_stdio(Path::new("./-.csv")));
+    }
+
+    #[test]
+    fn gzip_input_is_decompressed() {
+        let path = std::env::temp_dir()
+            .join(format!("gmaps-coords-{}-files.csv.gz", std::process::id()));
+        let mut encoder = GzEncoder::new(vec![], Compression::default());
+        encoder.write_all(b"Title,URL\n").unwrap();
+        write_atomically(&path, encoder.finish().unwrap()).unwrap();
+        let contents = read_input_to_string(&path);
+        fs::remove_file(&path).unwrap();
+        assert_eq!(contents.unwrap(), "Title,URL\n");
+    }
+}
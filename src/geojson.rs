@@ -0,0 +1,429 @@
+//! Filling in the missing coordinates of GeoJSON features.
+
+use std::{collections::HashMap, path::Path, str::FromStr};
+
+use futures::StreamExt;
+use geojson::{Feature, FeatureCollection, GeoJson, Geometry, Value};
+use tracing::{debug, warn};
+
+use crate::{
+    driver::{coords_stream, next_step, ClientPool, LookupOptions},
+    files::read_input_to_string,
+    page::Page,
+    GmapsError, LatLng, Progress, Result,
+};
+
+// How close to null island (0, 0), in degrees, a point is still treated as
+// missing coordinate data by default. Some exporters add a little noise.
+pub(crate) const NULL_ISLAND_EPSILON: f64 = 1e-6;
+
+/// Read a GeoJSON file. A lone Feature or Geometry, as some exporters write,
+/// is read as a collection of just that feature.
+pub(crate) fn read_geojson(input_path: &Path) -> Result<FeatureCollection> {
+    let geojson = GeoJson::from_str(&read_input_to_string(input_path)?)?;
+    let feature = match geojson {
+        GeoJson::FeatureCollection(feature_collection) => {
+            return Ok(feature_collection)
+        }
+        GeoJson::Feature(feature) => feature,
+        GeoJson::Geometry(geometry) => Feature::from(geometry),
+    };
+    Ok(FeatureCollection {
+        bbox: None,
+        features: vec![feature],
+        foreign_members: None,
+    })
+}
+
+/// Update a GeoJSON with missing coordinate data.
+///
+/// Features with a point at or near null island (0, 0), or no geometry at
+/// all, and a `google_maps_url` property get their coordinates looked up, or
+/// every point feature with one if `force_refetch` is set. If
+/// `only_changed_places` is set, only the features whose coordinates were
+/// updated are returned, along with any features with non-Point geometries,
+/// which are passed through unchanged. Everything else in the collection and
+/// its features, such as ids and foreign members, is kept as it is.
+///
+/// Features looked up get a `resolved_at` property with the time if
+/// `stamp_time` is set, and a `resolved_via` property with how they were found
+/// if `annotate_method` is set. `on_progress` is called with the updated
+/// collection so far each time a place has been looked up.
+pub async fn fill_geojson_coords<P: Page>(
+    pool: &ClientPool<P>,
+    mut feature_collection: FeatureCollection,
+    only_changed_places: bool,
+    lookup: &LookupOptions,
+    mut on_progress: impl FnMut(Progress),
+) -> FeatureCollection {
+    let mut features = std::mem::take(&mut feature_collection.features);
+    let mut found = HashMap::new();
+    // the features sharing each url, which is only looked up once
+    let mut indices: Vec<Vec<usize>> = vec![];
+    let mut urls = vec![];
+    let mut positions: HashMap<&str, usize> = HashMap::new();
+    for (i, feature) in features.iter().enumerate() {
+        if is_non_point(feature) {
+            debug!(feature = i, "Skipping feature with non-Point geometry");
+        } else if let Some(url) = lookup_url(feature, lookup) {
+            match lookup.resolved.get(url) {
+                Some(coords) => {
+                    found.insert(i, *coords);
+                }
+                None => match positions.get(url) {
+                    Some(&pos) => indices[pos].push(i),
+                    None => {
+                        positions.insert(url, urls.len());
+                        indices.push(vec![i]);
+                        urls.push(url.to_string());
+                    }
+                },
+            }
+        }
+    }
+    let indices = lookup.selected(indices);
+    let urls = lookup.selected(urls);
+
+    // the input index of each output feature, to update them in place
+    let mut output_indices;
+    (feature_collection.features, output_indices) =
+        updated_features(&features, &found, only_changed_places);
+    let (mut succeeded, mut failed) = (0, 0);
+    let mut results = coords_stream(pool, &urls, &[], lookup);
+    while let Some((i, result)) = results.next().await {
+        let mut found_feature = None;
+        let mut failures = vec![];
+        match result {
+            Ok((coords, resolution, _)) => {
+                succeeded += 1;
+                let resolved_at = lookup.resolved_at();
+                for &index in &indices[i] {
+                    if let Some(resolved_at) = &resolved_at {
+                        features[index]
+                            .set_property("resolved_at", resolved_at.clone());
+                    }
+                    if lookup.annotate_method {
+                        features[index]
+                            .set_property("resolved_via", resolution.name());
+                    }
+                    place_feature(
+                        &mut feature_collection.features,
+                        &mut output_indices,
+                        index,
+                        updated_feature(features[index].clone(), coords),
+                    );
+                }
+                found_feature = Some(updated_feature(
+                    features[indices[i][0]].clone(),
+                    coords,
+                ));
+            }
+            Err(e) => {
+                failed += 1;
+                warn!(
+                    "Failed to retrieve coordinates for record {} with \
+                    error {e:#}. {}",
+                    urls[i],
+                    next_step(lookup)
+                );
+                failures = indices[i]
+                    .iter()
+                    .map(|&index| {
+                        (index, failed_feature(features[index].clone(), &e))
+                    })
+                    .collect();
+            }
+        }
+        on_progress(Progress {
+            features: &feature_collection,
+            found: found_feature.as_ref(),
+            succeeded,
+            failed,
+            total: urls.len(),
+            failures: &failures,
+        });
+        if failed > 0 && lookup.fail_fast {
+            break;
+        }
+    }
+
+    feature_collection
+}
+
+/// Update the coordinates of features that were found, by feature index,
+/// along with the index of each feature kept.
+fn updated_features(
+    features: &[Feature],
+    found: &HashMap<usize, LatLng>,
+    only_changed_places: bool,
+) -> (Vec<Feature>, Vec<usize>) {
+    features
+        .iter()
+        .enumerate()
+        .filter_map(|(i, feature)| match found.get(&i) {
+            Some(new_coords) => {
+                Some((updated_feature(feature.clone(), *new_coords), i))
+            }
+            None => (!only_changed_places || is_non_point(feature))
+                .then(|| (feature.clone(), i)),
+        })
+        .unzip()
+}
+
+/// Put the feature of the input feature or record at the index in its place
+/// among the output features, whose input indices are `indices`, replacing
+/// the one already there. This keeps the output in input order without
+/// rebuilding it each time a place is found.
+pub(crate) fn place_feature(
+    features: &mut Vec<Feature>,
+    indices: &mut Vec<usize>,
+    index: usize,
+    feature: Feature,
+) {
+    match indices.binary_search(&index) {
+        Ok(pos) => features[pos] = feature,
+        Err(pos) => {
+            indices.insert(pos, index);
+            features.insert(pos, feature);
+        }
+    }
+}
+
+/// Update the coordinates of a feature, adding a point if it has no geometry.
+/// A bounding box of the feature is moved to the new point too, since one
+/// left around the old point, e.g. at null island, wouldn't contain the
+/// feature's geometry any more, as GeoJSON requires.
+fn updated_feature(mut feature: Feature, new_coords: LatLng) -> Feature {
+    match feature.geometry {
+        Some(Geometry {
+            value: Value::Point(ref mut coords),
+            ..
+        }) => {
+            // keep the altitude if only the lng, lat were missing
+            let alt = new_coords.alt.or(coords.get(2).copied());
+            *coords = LatLng { alt, ..new_coords }.to_geojson_point();
+        }
+        None => {
+            feature.geometry =
+                Some(Value::Point(new_coords.to_geojson_point()).into());
+        }
+        Some(_) => return feature,
+    }
+    if let Some(bbox) = &mut feature.bbox {
+        let LatLng { lat, lng, .. } = new_coords;
+        *bbox = vec![lng, lat, lng, lat];
+    }
+    feature
+}
+
+/// Whether a feature has a geometry other than a Point, which has no single
+/// coordinate to look up.
+fn is_non_point(feature: &Feature) -> bool {
+    !matches!(
+        feature.geometry,
+        None | Some(Geometry {
+            value: Value::Point(_),
+            ..
+        })
+    )
+}
+
+/// The Google Maps url of a feature that needs looking up, because it's
+/// missing coordinate data or every point is being looked up.
+pub(crate) fn lookup_url<'a>(
+    feature: &'a Feature,
+    lookup: &LookupOptions,
+) -> Option<&'a str> {
+    let missing = match feature.geometry {
+        // at null island, missing coordinate data
+        Some(Geometry {
+            value: Value::Point(ref coords),
+            ..
+        }) => {
+            lookup.force_refetch
+                || is_null_island(coords, lookup.null_island_epsilon)
+        }
+        // e.g. hand-edited files with only the urls filled in
+        None => true,
+        Some(_) => false,
+    };
+    if !missing {
+        return None;
+    }
+    feature
+        .properties
+        .as_ref()
+        .and_then(|p| p.get("google_maps_url"))
+        .and_then(|v| v.as_str())
+}
+
+/// The [min lng, min lat, max lng, max lat] of every point that isn't
+/// missing coordinate data, if there are any.
+pub(crate) fn bounding_box(
+    features: &FeatureCollection,
+    null_island_epsilon: f64,
+) -> Option<Vec<f64>> {
+    features
+        .features
+        .iter()
+        .filter_map(|feature| match feature.geometry {
+            Some(Geometry {
+                value: Value::Point(ref coords),
+                ..
+            }) if !is_null_island(coords, null_island_epsilon) => {
+                LatLng::from_geojson_point(coords)
+            }
+            _ => None,
+        })
+        .fold(None, |bbox, p| {
+            let [min_lng, min_lat, max_lng, max_lat] =
+                bbox.unwrap_or([p.lng, p.lat, p.lng, p.lat]);
+            Some([
+                min_lng.min(p.lng),
+                min_lat.min(p.lat),
+                max_lng.max(p.lng),
+                max_lat.max(p.lat),
+            ])
+        })
+        .map(Vec::from)
+}
+
+/// Whether a point's lng, lat coordinates are within epsilon degrees of null
+/// island (0, 0).
+pub(crate) fn is_null_island(coords: &[f64], epsilon: f64) -> bool {
+    match (coords.first(), coords.get(1)) {
+        (Some(lng), Some(lat)) => lng.abs() <= epsilon && lat.abs() <= epsilon,
+        _ => false,
+    }
+}
+
+/// The feature of a place that couldn't be looked up, with the error.
+pub(crate) fn failed_feature(
+    mut feature: Feature,
+    error: &GmapsError,
+) -> Feature {
+    feature.set_property("error", error.to_string());
+    feature
+}
+
+#[cfg(test)]
+mod tests {
+    use geojson::JsonObject;
+    use serde_json::json;
+
+    use super::*;
+
+    const URL: &str = "https://www.google.com/maps/place/A/data=!3d1.5!4d2.5";
+
+    fn feature(value: Value) -> Feature {
+        let mut properties = JsonObject::new();
+        properties.insert("google_maps_url".into(), URL.into());
+        Feature {
+            geometry: Some(value.into()),
+            properties: Some(properties),
+            ..Default::default()
+        }
+    }
+
+    async fn filled(
+        features: Vec<Feature>,
+        only_changed_places: bool,
+    ) -> Vec<Feature> {
+        let feature_collection = FeatureCollection {
+            bbox: None,
+            features,
+            foreign_members: None,
+        };
+        // every url has its coordinates in it, so the browser isn't needed
+        fill_geojson_coords(
+            &ClientPool::empty(),
+            feature_collection,
+            only_changed_places,
+            &LookupOptions::default(),
+            |_| {},
+        )
+        .await
+        .features
+    }
+
+    #[tokio::test]
+    async fn non_point_geometries_pass_through() {
+        let line = feature(Value::LineString(vec![vec![0.0, 0.0]; 2]));
+        let polygon = feature(Value::Polygon(vec![vec![
+            vec![0.0, 0.0],
+            vec![1.0, 0.0],
+            vec![1.0, 1.0],
+            vec![0.0, 0.0],
+        ]]));
+        let point = feature(Value::Point(vec![0.0, 0.0]));
+        for only_changed_places in [false, true] {
+            let features = filled(
+                vec![line.clone(), polygon.clone(), point.clone()],
+                only_changed_places,
+            )
+            .await;
+            assert_eq!(features.len(), 3);
+            assert_eq!(features[0], line);
+            assert_eq!(features[1], polygon);
+            let expected = json!({"type": "Point", "coordinates": [2.5, 1.5]});
+            assert_eq!(json!(features[2].geometry), expected);
+        }
+    }
+
+    #[tokio::test]
+    async fn ids_and_foreign_members_round_trip() {
+        let input = json!({
+            "type": "FeatureCollection",
+            "name": "Saved places",
+            "crs": {
+                "type": "name",
+                "properties": {"name": "urn:ogc:def:crs:OGC:1.3:CRS84"}
+            },
+            "features": [{
+                "type": "Feature",
+                "id": "place-1",
+                "bbox": [0.0, 0.0, 0.0, 0.0],
+                "geometry": {"type": "Point", "coordinates": [0.0, 0.0]},
+                "properties": {"google_maps_url": URL},
+                "source": "takeout"
+            }]
+        });
+        let feature_collection =
+            FeatureCollection::from_str(&input.to_string()).unwrap();
+        let output = fill_geojson_coords(
+            &ClientPool::empty(),
+            feature_collection,
+            false,
+            &LookupOptions::default(),
+            |_| {},
+        )
+        .await;
+        let output = serde_json::to_value(&output).unwrap();
+        assert_eq!(output["name"], input["name"]);
+        assert_eq!(output["crs"], input["crs"]);
+        let feature = &output["features"][0];
+        assert_eq!(feature["id"], "place-1");
+        assert_eq!(feature["source"], "takeout");
+        assert_eq!(feature["geometry"]["coordinates"], json!([2.5, 1.5]));
+        assert_eq!(feature["bbox"], json!([2.5, 1.5, 2.5, 1.5]));
+    }
+
+    #[test]
+    fn placed_features_stay_in_input_order() {
+        let named = |name: &str| {
+            let mut feature = Feature::default();
+            feature.set_property("name", name);
+            feature
+        };
+        let (mut features, mut indices) = (vec![named("b")], vec![1]);
+        for (index, name) in [(3, "d"), (0, "a"), (2, "c"), (1, "B")] {
+            place_feature(&mut features, &mut indices, index, named(name));
+        }
+        let names: Vec<_> = features
+            .iter()
+            .map(|f| f.property("name").unwrap())
+            .collect();
+        assert_eq!(names, ["a", "B", "c", "d"]);
+        assert_eq!(indices, [0, 1, 2, 3]);
+    }
+}
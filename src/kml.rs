@@ -0,0 +1,170 @@
+//! Reading places from KML and KMZ files.
+
+use std::{
+    io::{self, Read},
+    path::Path,
+    sync::LazyLock,
+};
+
+use quick_xml::events::Event;
+use regex::Regex;
+use tracing::warn;
+
+use crate::{
+    csv::Record,
+    extract::is_google_maps_url,
+    files::{format_extension, open_input, read_input_to_string},
+    GmapsError, Result,
+};
+
+/// Read a KML or KMZ file of placemarks.
+pub(crate) fn read_kml(input_path: &Path) -> Result<Vec<Record>> {
+    let kml = match format_extension(input_path) {
+        Some("kmz") => read_kmz(input_path)?,
+        _ => read_input_to_string(input_path)?,
+    };
+    kml_records(&kml)
+}
+
+/// Read the KML document out of a KMZ archive. This is `doc.kml` if present,
+/// otherwise the first KML file in the archive.
+fn read_kmz(path: &Path) -> Result<String> {
+    // zip archives need seeking, which stdin can't do
+    let mut bytes = vec![];
+    open_input(path)?.read_to_end(&mut bytes)?;
+    let mut archive = zip::ZipArchive::new(io::Cursor::new(bytes))?;
+    let name = match archive.index_for_name("doc.kml") {
+        Some(_) => "doc.kml".to_string(),
+        None => match archive.file_names().find(|n| n.ends_with(".kml")) {
+            Some(name) => name.to_string(),
+            None => {
+                return Err(GmapsError::Parse(
+                    "No KML file found in KMZ archive".into(),
+                ))
+            }
+        },
+    };
+    let mut kml = String::new();
+    archive.by_name(&name)?.read_to_string(&mut kml)?;
+    Ok(kml)
+}
+
+// A link in a placemark's description.
+static HREF_RE: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r#"href="([^"]+)""#).unwrap());
+
+/// Parse the placemarks in a KML document into records. The url of each
+/// record is the first Google Maps link in the placemark's description, and
+/// placemarks without one are skipped.
+fn kml_records(kml: &str) -> Result<Vec<Record>> {
+    let mut reader = quick_xml::Reader::from_str(kml);
+    let mut records = vec![];
+    // name and description of the placemark being read
+    let mut placemark: Option<(String, String)> = None;
+    let mut in_name = false;
+    let mut in_description = false;
+    loop {
+        let text = match reader.read_event()? {
+            Event::Start(e) => {
+                match e.local_name().as_ref() {
+                    b"Placemark" => placemark = Some(Default::default()),
+                    b"name" => in_name = true,
+                    b"description" => in_description = true,
+                    _ => {}
+                }
+                continue;
+            }
+            Event::End(e) => {
+                match e.local_name().as_ref() {
+                    b"Placemark" => {
+                        if let Some((name, description)) = placemark.take() {
+                            let url = HREF_RE
+                                .captures_iter(&description)
+                                .map(|c| c.extract::<1>().1[0])
+                                .find(|url| is_google_maps_url(url));
+                            match url {
+                                Some(url) => records.push(Record {
+                                    title: name.trim().to_string(),
+                                    url: url.to_string(),
+                                    note: Some(description.trim().to_string())
+                                        .filter(|d| !d.is_empty()),
+                                    comment: None,
+                                    extra: vec![],
+                                    coords: None,
+                                }),
+                                None => warn!(
+                                    "No Google Maps link found for placemark \
+                                    {}. Continuing.",
+                                    name.trim()
+                                ),
+                            }
+                        }
+                    }
+                    b"name" => in_name = false,
+                    b"description" => in_description = false,
+                    _ => {}
+                }
+                continue;
+            }
+            Event::Text(t) => t.unescape()?.into_owned(),
+            Event::CData(t) => String::from_utf8_lossy(&t).into_owned(),
+            Event::Eof => break,
+            _ => continue,
+        };
+        if let Some((name, description)) = placemark.as_mut() {
+            if in_name {
+                name.push_str(&text);
+            } else if in_description {
+                description.push_str(&text);
+            }
+        }
+    }
+    Ok(records)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use zip::{write::SimpleFileOptions, ZipWriter};
+
+    use super::*;
+
+    const KML: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<kml xmlns="http://www.opengis.net/kml/2.2"><Document>
+  <Placemark>
+    <name> Cafe &amp; Bar </name>
+    <description><![CDATA[<a href="https://example.com">Site</a>
+      <a href="https://maps.google.com/?cid=123">Map</a>]]></description>
+  </Placemark>
+  <Placemark>
+    <name>No link</name>
+    <description>Just a note</description>
+  </Placemark>
+</Document></kml>"#;
+
+    #[test]
+    fn placemarks_with_a_google_maps_link() {
+        let records = kml_records(KML).unwrap();
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].title, "Cafe & Bar");
+        assert_eq!(records[0].url, "https://maps.google.com/?cid=123");
+        assert!(records[0].note.as_ref().unwrap().contains("Site"));
+    }
+
+    #[test]
+    fn kmz_doc_kml() {
+        let path = std::env::temp_dir()
+            .join(format!("gmaps-coords-{}-places.kmz", std::process::id()));
+        let mut zip = ZipWriter::new(io::Cursor::new(vec![]));
+        let options = SimpleFileOptions::default();
+        zip.start_file("other.kml", options).unwrap();
+        zip.write_all(b"<kml/>").unwrap();
+        zip.start_file("doc.kml", options).unwrap();
+        zip.write_all(KML.as_bytes()).unwrap();
+        std::fs::write(&path, zip.finish().unwrap().into_inner()).unwrap();
+        let records = read_kml(&path);
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(records.unwrap()[0].title, "Cafe & Bar");
+    }
+}
@@ -1,257 +1,290 @@
-use std::{fs, io::Write, path::PathBuf, str::FromStr};
+mod cli;
+mod csv;
+mod driver;
+mod extract;
+mod files;
+mod geojson;
+mod kml;
+mod output;
+mod page;
 
-use anyhow::{bail, Result};
-use clap::Parser;
-use fantoccini::{Client, ClientBuilder};
-use geojson::{Feature, FeatureCollection, Geometry, JsonObject, Value};
-use regex::Regex;
-use serde::Deserialize;
-use tokio::time::{sleep, Duration};
+pub use cli::run;
+pub use csv::{records_to_geojson, CsvColumns, Encoding, Record};
+pub use driver::{
+    resolve_url, Browser, ClientPool, CoordCache, LookupMethod, LookupOptions,
+    LookupReport, LookupTiming, RateLimiter, ReportSummary, WaitStrategy,
+};
+pub use extract::{
+    default_extractors, AppleExtractor, CoordExtractor, CoordParamExtractor,
+    DataParamExtractor, MapCenterExtractor, OsmExtractor, Provider,
+};
+pub use geojson::fill_geojson_coords;
+pub use page::{Page, Selector};
 
-// A latitude,longitude regex pattern. E.g. "-25.0,160.0".
-// (?:) denotes a non-capturing group. ()? denotes an optional group.
-const LATLNGPAT: &str = r"(-?\d+(?:\.\d+)?),(-?\d+(?:\.\d+)?)";
+use std::io;
 
-/// Read GeoJSON and CSV files exported from Google Maps and converts them to
-/// GeoJSON files with coordinates for each place.
-///
-/// First run a WebDriver server in another terminal, such as geckodriver:
-///
-/// `cargo install geckodriver && geckodriver`
-#[derive(Debug, Parser)]
-#[command(version, about)]
-struct Cli {
-    /// Input filename
-    ///
-    /// If the extension is "csv", it is interpreted as CSV, otherwise it is
-    /// interpreted as GeoJSON. Lines with comments should be removed from CSV
-    /// files beforehand.
-    #[arg(short, long, value_name = "FILE")]
-    input: PathBuf,
+use ::geojson::{Feature, FeatureCollection};
 
-    /// Output filename, GeoJSON formatted
-    #[arg(short, long, value_name = "FILE")]
-    output: PathBuf,
-
-    /// (GeoJSON only) Only output features that got updated coordinates
-    #[arg(long)]
-    only_changed_places: bool,
+/// The coordinates of a place.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LatLng {
+    pub lat: f64,
+    pub lng: f64,
+    /// Altitude in meters, if known.
+    pub alt: Option<f64>,
+}
 
-    /// The port to connect to the WebDriver server. Defaults to 4444.
-    #[arg(short, long, value_name = "PORT")]
-    port: Option<u16>,
+impl LatLng {
+    /// The coordinates as a GeoJSON position, which is ordered lng, lat(, alt).
+    pub fn to_geojson_point(self) -> Vec<f64> {
+        match self.alt {
+            Some(alt) => vec![self.lng, self.lat, alt],
+            None => vec![self.lng, self.lat],
+        }
+    }
 
-    /// Show the browser as coordinates are looked up
-    #[arg(long)]
-    noheadless: bool,
+    /// Read the coordinates from a GeoJSON position, ordered lng, lat(, alt).
+    pub fn from_geojson_point(position: &[f64]) -> Option<Self> {
+        match position {
+            [lng, lat] => Some(LatLng {
+                lat: *lat,
+                lng: *lng,
+                alt: None,
+            }),
+            [lng, lat, alt, ..] => Some(LatLng {
+                lat: *lat,
+                lng: *lng,
+                alt: Some(*alt),
+            }),
+            _ => None,
+        }
+    }
 }
 
-/// Run the command-line interface
-pub async fn run() {
-    let cli = Cli::parse();
-
-    let opts = match cli.noheadless {
-        false => serde_json::json!({
-            "moz:firefoxOptions": {
-                "args": ["--headless"]
-            }
-        })
-        .as_object()
-        .unwrap()
-        .clone(),
-        true => serde_json::Map::new(),
-    };
-    let c = ClientBuilder::native()
-        .capabilities(opts)
-        .connect(&format!("http://localhost:{}", cli.port.unwrap_or(4444)))
-        .await
-        .expect("Failed to connect to WebDriver");
+/// A region that places are expected to be in.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Bounds {
+    pub min_lng: f64,
+    pub min_lat: f64,
+    pub max_lng: f64,
+    pub max_lat: f64,
+}
 
-    // check that we can write to the output file, without overwriting, before
-    // spending lots of time fetching coordinates
-    fs::OpenOptions::new()
-        .create(true)
-        .write(true)
-        .open(&cli.output)
-        .expect("Cannot write to output file");
+impl Bounds {
+    /// Whether the coordinates are in the region. A region whose minimum
+    /// longitude is greater than its maximum crosses the antimeridian.
+    pub fn contains(&self, coords: LatLng) -> bool {
+        let LatLng { lat, lng, .. } = coords;
+        let lng_in = match self.min_lng <= self.max_lng {
+            true => (self.min_lng..=self.max_lng).contains(&lng),
+            false => lng >= self.min_lng || lng <= self.max_lng,
+        };
+        lng_in && (self.min_lat..=self.max_lat).contains(&lat)
+    }
+}
 
-    let features = match cli.input.extension().and_then(|e| e.to_str()) {
-        Some("csv") => run_csv(&c, &cli.input).await,
-        _ => run_geojson(&c, &cli.input, cli.only_changed_places).await,
+/// Parse a region given as "MIN_LNG,MIN_LAT,MAX_LNG,MAX_LAT".
+fn parse_bounds(s: &str) -> Result<Bounds, String> {
+    let numbers: Vec<f64> = s
+        .split(',')
+        .map(|n| n.trim().parse::<f64>())
+        .collect::<Result<_, _>>()
+        .map_err(|e| e.to_string())?;
+    let [min_lng, min_lat, max_lng, max_lat] = numbers[..] else {
+        return Err("expected MIN_LNG,MIN_LAT,MAX_LNG,MAX_LAT".into());
     };
+    if min_lat > max_lat {
+        return Err("the minimum latitude is greater than the maximum".into());
+    }
+    Ok(Bounds {
+        min_lng,
+        min_lat,
+        max_lng,
+        max_lat,
+    })
+}
 
-    let mut file =
-        fs::File::create(&cli.output).expect("Failed to create output file");
-    file.write_all(features.to_string().as_bytes())
-        .expect("Failed to write to output file");
+/// The result of the library's functions.
+pub type Result<T, E = GmapsError> = std::result::Result<T, E>;
 
-    c.close().await.expect("Closing WebDriver client");
+/// Why a place's coordinates couldn't be found, or a file couldn't be read.
+#[derive(Debug, thiserror::Error)]
+pub enum GmapsError {
+    /// The text has no coordinates in it.
+    #[error("No coordinates found in {0}")]
+    NoCoordinates(String),
+    /// The page didn't show the place's coordinates in time.
+    #[error(
+        "Failed to get coordinates for {url} before timeout of {secs} seconds"
+    )]
+    Timeout { url: String, secs: f64 },
+    /// The coordinates are outside the valid latitude and longitude ranges.
+    #[error("Coordinates {lat},{lng} are out of range")]
+    InvalidCoordinates { lat: f64, lng: f64 },
+    /// The coordinates found are outside the region places are expected in.
+    #[error("Coordinates {lat},{lng} found for {url} are out of bounds")]
+    OutOfBounds { url: String, lat: f64, lng: f64 },
+    /// The url is a search listing several places rather than a single one.
+    #[error("{0} is a search with several results rather than a single place")]
+    AmbiguousPlace(String),
+    /// The place's page has no name.
+    #[error("No name found on the page of {0}")]
+    NoName(String),
+    #[error(transparent)]
+    Blocked(#[from] BlockedError),
+    #[error(transparent)]
+    WebDriver(#[from] WebDriverError),
+    /// A lookup was retried after WebDriver errors, and still failed. The
+    /// error is the last attempt's, which may be of any kind, e.g. a timeout.
+    #[error("Failed after {attempts} attempts: {error}")]
+    RetriesExhausted {
+        attempts: u32,
+        error: Box<GmapsError>,
+    },
+    /// A CSV row is missing one of the required columns.
+    #[error("CSV row has no value in the required column {0}")]
+    MissingColumn(String),
+    /// A CSV's header is missing some of the required columns.
+    #[error(
+        "CSV is missing the column(s) {}. Its columns are {}",
+        .missing.join(", "),
+        .found.join(", ")
+    )]
+    MissingColumns {
+        missing: Vec<String>,
+        found: Vec<String>,
+    },
+    #[error(transparent)]
+    Io(#[from] io::Error),
+    /// A file couldn't be parsed.
+    #[error("Failed to parse: {0}")]
+    Parse(Box<dyn std::error::Error + Send + Sync>),
 }
 
-/// Update a GeoJSON with missing coordiante data.
-async fn run_geojson(
-    c: &Client,
-    input_path: &PathBuf,
-    only_change_places: bool,
-) -> FeatureCollection {
-    let mut feature_collection = FeatureCollection::from_str(
-        &fs::read_to_string(input_path).expect("Failed to read file"),
-    )
-    .expect("Failed to parse input as GeoJSON");
-
-    let mut new_features = vec![];
-    for mut feature in feature_collection.features.into_iter() {
-        if let Some(Geometry {
-            value: Value::Point(ref mut coords),
-            ..
-        }) = feature.geometry
-        {
-            if let (Some(lng), Some(lat)) = (coords.first(), coords.get(1)) {
-                if *lng == 0.0 && *lat == 0.0 {
-                    // at null island, missing coordinate data
-                    if let Some(url) = feature
-                        .properties
-                        .as_ref()
-                        .and_then(|p| p.get("google_maps_url"))
-                        .and_then(|v| v.as_str())
-                    {
-                        match get_coords_for_url(c, url).await {
-                            Ok(new_coords) => {
-                                // update coords and move feature to output vec
-                                *coords = new_coords;
-                                new_features.push(feature);
-                                continue;
-                            }
-                            Err(e) => {
-                                eprintln!(
-                                    "Failed to retrieve coordinates for record \
-                                    {url} with error {e}. Continuing."
-                                );
-                            }
-                        };
-                    }
+// errors from parsing each of the formats that are read
+macro_rules! impl_from_parse_error {
+    ($($error:ty),*) => {
+        $(
+            impl From<$error> for GmapsError {
+                fn from(e: $error) -> Self {
+                    GmapsError::Parse(Box::new(e))
                 }
             }
-        }
-        if !only_change_places {
-            new_features.push(feature);
-        }
-    }
-    feature_collection.features = new_features;
-    feature_collection
+        )*
+    };
 }
 
-/// Go to the url and get the coordinates of the place, returned as lng, lat.
-async fn get_coords_for_url(c: &Client, url: &str) -> Result<Vec<f64>> {
-    // if url contains a coordinate query, the map will not be centered, so
-    // just get the coordinates from the url
-    let pattern = Regex::new(&format!("{}{}", "q=", LATLNGPAT)).unwrap();
-    if let Ok(coords) = coords_from_regex(&pattern, url) {
-        return Ok(coords);
-    }
+impl_from_parse_error!(
+    ::csv::Error,
+    ::geojson::Error,
+    quick_xml::Error,
+    serde_json::Error,
+    std::num::ParseFloatError,
+    zip::result::ZipError
+);
 
-    // pattern to match in url when it updates with the view center
-    let pattern = Regex::new(&format!("{}{}", "@", LATLNGPAT)).unwrap();
-    c.goto(url).await?;
-    for i in 0..100 {
-        // wait up to 10 seconds total
-        sleep(Duration::from_millis(100)).await;
-        let redirected_url = c.current_url().await?;
-        if redirected_url.as_str() != url {
-            if let Ok(coords) =
-                coords_from_regex(&pattern, redirected_url.as_str())
-            {
-                println!("Fetched coordinates in {} seconds", i as f64 / 10.0,);
-                return Ok(coords);
-            }
-        }
-    }
-    bail!("Failed to get coordinates before timeout");
+/// A WebDriver command that failed, classified by what went wrong, with the
+/// error from whichever library sent it, so a [`Page`] needn't be driven by
+/// fantoccini.
+#[derive(Debug, thiserror::Error)]
+pub enum WebDriverError {
+    /// The browser refused to start a session.
+    #[error("WebDriver session not created: {0}")]
+    SessionNotCreated(Box<dyn std::error::Error + Send + Sync>),
+    /// The page didn't load in time.
+    #[error("Timed out loading the page: {0}")]
+    NavigationTimeout(Box<dyn std::error::Error + Send + Sync>),
+    /// The browser's tab crashed.
+    #[error("The browser crashed: {0}")]
+    RendererCrashed(Box<dyn std::error::Error + Send + Sync>),
+    /// The session is gone, e.g. because the browser was closed.
+    #[error("Lost the WebDriver session: {0}")]
+    SessionLost(Box<dyn std::error::Error + Send + Sync>),
+    /// Any other WebDriver error.
+    #[error(transparent)]
+    Other(Box<dyn std::error::Error + Send + Sync>),
 }
 
-/// Parse the coordinates contained in text, according to the given regex.
-fn coords_from_regex(pattern: &Regex, text: &str) -> Result<Vec<f64>> {
-    if let Some((_, [lat, lng])) =
-        pattern.captures_iter(text).map(|c| c.extract()).next()
-    {
-        let lat = lat.parse::<f64>()?;
-        let lng = lng.parse::<f64>()?;
-        Ok(vec![lng, lat])
-    } else {
-        bail!("No coordinates found in text")
-    }
+/// Google showed a page that stops a place from loading.
+#[derive(Debug, thiserror::Error)]
+pub enum BlockedError {
+    /// Google wants cookie consent before showing the place.
+    #[error(
+        "Google showed a consent page at {0} instead of the place. Run with \
+        --noheadless --accept-consent to accept it."
+    )]
+    ConsentRequired(String),
+    /// Google suspects automated traffic.
+    #[error(
+        "Google showed a CAPTCHA at {0} instead of the place. Try again \
+        later, or with a larger --min-delay-ms."
+    )]
+    CaptchaEncountered(String),
 }
 
-/// The expected CSV structure.
-#[derive(Debug, Deserialize)]
-struct Record {
-    #[serde(rename = "Title")]
-    title: String,
-    #[serde(rename = "Note")]
-    note: Option<String>,
-    #[serde(rename = "URL")]
-    url: String,
-    #[serde(rename = "Comment")]
-    comment: Option<String>,
+/// How far through looking up the places a conversion is.
+#[derive(Debug, Clone, Copy)]
+pub struct Progress<'a> {
+    /// The output so far.
+    pub features: &'a FeatureCollection,
+    /// The feature that coordinates were just found for, if any.
+    pub found: Option<&'a Feature>,
+    /// How many places coordinates were found for.
+    pub succeeded: usize,
+    /// How many places coordinates couldn't be found for.
+    pub failed: usize,
+    /// How many places are being looked up in total.
+    pub total: usize,
+    /// The features that coordinates just couldn't be found for, with an
+    /// `error` property, by their index in the input.
+    pub failures: &'a [(usize, Feature)],
 }
 
-/// Convert a CSV of locations without coordinates to GeoJSON by looking up the
-/// locations.
-async fn run_csv(c: &Client, input_path: &PathBuf) -> FeatureCollection {
-    let mut rdr = csv::ReaderBuilder::new()
-        .from_path(input_path)
-        .expect("Failed to read CSV file");
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-    let mut records_and_coords = vec![];
-    for result in rdr.deserialize::<Record>() {
-        match result {
-            Ok(record) => {
-                match get_coords_for_url(c, &record.url).await {
-                    Ok(coords) => {
-                        records_and_coords.push((record, coords));
-                    }
-                    Err(e) => {
-                        eprintln!(
-                            "Failed to retrieve coordinates for record \
-                            {record:?} with error {e}. Continuing."
-                        );
-                    }
-                };
-            }
-            Err(e) => {
-                eprintln!(
-                    "Failed to parse CSV record with error {e}. Continuing."
-                );
-            }
-        };
+    fn at(lat: f64, lng: f64) -> LatLng {
+        LatLng {
+            lat,
+            lng,
+            alt: None,
+        }
     }
 
-    FeatureCollection {
-        features: records_and_coords
-            .into_iter()
-            .map(record_and_coords_to_feature)
-            .collect(),
-        bbox: None,
-        foreign_members: None,
+    #[test]
+    fn bounds_contain_their_edges() {
+        let bounds = parse_bounds("-10, 40, 5, 60").unwrap();
+        assert!(bounds.contains(at(40.0, -10.0)));
+        assert!(bounds.contains(at(60.0, 5.0)));
+        assert!(!bounds.contains(at(39.9, 0.0)));
+        assert!(!bounds.contains(at(50.0, 5.1)));
     }
-}
 
-/// Convert tuples of (CSV record, coordinates) to GeoJSON features.
-fn record_and_coords_to_feature(
-    (record, coords): (Record, Vec<f64>),
-) -> Feature {
-    let mut properties = JsonObject::new();
-    properties.insert("name".into(), record.title.into());
-    properties.insert("google_maps_url".into(), record.url.into());
-    if let Some(note) = record.note {
-        properties.insert("note".into(), note.into());
+    #[test]
+    fn bounds_across_the_antimeridian() {
+        let bounds = parse_bounds("170,-50,-170,-30").unwrap();
+        assert!(bounds.contains(at(-40.0, 175.0)));
+        assert!(bounds.contains(at(-40.0, -175.0)));
+        assert!(!bounds.contains(at(-40.0, 0.0)));
     }
-    if let Some(comment) = record.comment {
-        properties.insert("comment".into(), comment.into());
+
+    #[test]
+    fn invalid_bounds() {
+        assert!(parse_bounds("1,2,3").is_err());
+        assert!(parse_bounds("1,2,3,x").is_err());
+        assert!(parse_bounds("0,10,5,0").is_err());
     }
-    Feature {
-        geometry: Some(Value::Point(coords).into()),
-        properties: Some(properties),
-        ..Default::default()
+
+    #[test]
+    fn geojson_points_are_lng_lat() {
+        let coords = LatLng {
+            alt: Some(3.0),
+            ..at(1.0, 2.0)
+        };
+        assert_eq!(coords.to_geojson_point(), [2.0, 1.0, 3.0]);
+        assert_eq!(LatLng::from_geojson_point(&[2.0, 1.0, 3.0]), Some(coords));
+        assert_eq!(LatLng::from_geojson_point(&[2.0, 1.0]), Some(at(1.0, 2.0)));
+        assert_eq!(LatLng::from_geojson_point(&[2.0]), None);
     }
 }
@@ -1,7 +1,15 @@
 //! Consumes a CSV or GeoJSON containing Google Maps URLs, finds the coordinates
 //! of each place, and outputs a GeoJSON file with the coordinate data.
 
+use std::process::ExitCode;
+
 #[tokio::main]
-async fn main() {
-    gmaps_coords::run().await;
+async fn main() -> ExitCode {
+    match gmaps_coords::run().await {
+        Ok(code) => code,
+        Err(e) => {
+            eprintln!("Error: {e:#}");
+            ExitCode::FAILURE
+        }
+    }
 }
@@ -0,0 +1,446 @@
+//! Writing places with coordinates in each output format.
+
+use std::{
+    collections::HashMap,
+    io::{self, Write},
+    path::Path,
+    str::FromStr,
+};
+
+use clap::ValueEnum;
+use flate2::{write::GzEncoder, Compression};
+use geojson::{Feature, FeatureCollection, Geometry, Value};
+use quick_xml::escape::escape;
+
+use crate::{
+    csv::{csv_features, to_csv},
+    files::{
+        format_extension, is_gzip, is_stdio, read_input_to_string,
+        write_atomically,
+    },
+    geojson::is_null_island,
+    LatLng, Result,
+};
+
+/// A file format to write the places with coordinates to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub(crate) enum OutputFormat {
+    /// A GeoJSON FeatureCollection
+    Geojson,
+    /// GPX waypoints
+    Gpx,
+    /// One GeoJSON Feature per line, written as each place is found when
+    /// writing to stdout
+    Ndjson,
+    /// A CSV of the places' properties, with latitude and longitude columns
+    Csv,
+}
+
+impl OutputFormat {
+    /// The format implied by the path's extension.
+    pub(crate) fn from_path(path: &Path) -> Self {
+        match format_extension(path) {
+            Some("gpx") => OutputFormat::Gpx,
+            Some("ndjson" | "jsonl") => OutputFormat::Ndjson,
+            Some("csv") => OutputFormat::Csv,
+            _ => OutputFormat::Geojson,
+        }
+    }
+}
+
+/// How the features are written to the output file.
+pub(crate) struct OutputOptions {
+    pub(crate) format: OutputFormat,
+    pub(crate) null_island_epsilon: f64,
+    /// The number of decimal places to round coordinates to, if any.
+    pub(crate) precision: Option<u32>,
+    /// Whether GeoJSON is indented rather than compact.
+    pub(crate) pretty: bool,
+    pub(crate) properties: PropertyMap,
+    /// The columns CSV output starts with, in order, e.g. those of a CSV
+    /// input.
+    pub(crate) columns: Vec<String>,
+}
+
+impl OutputOptions {
+    /// The features as they're written, with their coordinates rounded and
+    /// their properties renamed.
+    fn output_features(
+        &self,
+        features: &FeatureCollection,
+    ) -> FeatureCollection {
+        let bbox = match (&features.bbox, self.precision) {
+            (Some(bbox), Some(p)) => Some(round_all(bbox, p)),
+            (bbox, _) => bbox.clone(),
+        };
+        FeatureCollection {
+            bbox,
+            features: features
+                .features
+                .iter()
+                .map(|f| self.output_feature(f))
+                .collect(),
+            foreign_members: features.foreign_members.clone(),
+        }
+    }
+
+    /// The feature as it's written, with its coordinates rounded and its
+    /// properties renamed. GPX has its own names for the properties, so they
+    /// aren't renamed for it.
+    pub(crate) fn output_feature(&self, feature: &Feature) -> Feature {
+        let mut feature = match self.precision {
+            Some(p) => rounded_feature(feature, p),
+            None => feature.clone(),
+        };
+        if self.format != OutputFormat::Gpx {
+            self.properties.rename(&mut feature);
+        }
+        feature
+    }
+}
+
+/// The output names of the properties of the places.
+#[derive(Debug, Clone)]
+pub(crate) struct PropertyMap {
+    pub(crate) name: String,
+    pub(crate) url: String,
+    pub(crate) note: String,
+    pub(crate) comment: String,
+}
+
+impl Default for PropertyMap {
+    fn default() -> Self {
+        PropertyMap {
+            name: "name".into(),
+            url: "google_maps_url".into(),
+            note: "note".into(),
+            comment: "comment".into(),
+        }
+    }
+}
+
+impl PropertyMap {
+    /// Pairs of the default names of the properties and their output names.
+    fn pairs(&self) -> [(&'static str, &str); 4] {
+        [
+            ("name", &self.name),
+            ("google_maps_url", &self.url),
+            ("note", &self.note),
+            ("comment", &self.comment),
+        ]
+    }
+
+    /// Rename the feature's properties to their output names.
+    fn rename(&self, feature: &mut Feature) {
+        for (from, to) in self.pairs() {
+            rename_property(feature, from, to);
+        }
+    }
+
+    /// Rename the feature's properties from their output names back to the
+    /// default names, e.g. for features read from an earlier output.
+    fn restore(&self, feature: &mut Feature) {
+        for (from, to) in self.pairs() {
+            rename_property(feature, to, from);
+        }
+    }
+}
+
+/// Move a property of the feature to a new key, if it has it.
+fn rename_property(feature: &mut Feature, from: &str, to: &str) {
+    if from == to {
+        return;
+    }
+    if let Some(value) =
+        feature.properties.as_mut().and_then(|p| p.remove(from))
+    {
+        feature.set_property(to, value);
+    }
+}
+
+/// Parse a comma-separated list of property renames, e.g.
+/// "title=label,url=source_url".
+pub(crate) fn parse_property_map(s: &str) -> Result<PropertyMap, String> {
+    let mut map = PropertyMap::default();
+    for rename in s.split(',').filter(|r| !r.trim().is_empty()) {
+        let Some((from, to)) = rename.split_once('=') else {
+            return Err(format!("expected PROPERTY=NAME, got {rename:?}"));
+        };
+        let to = to.trim();
+        if to.is_empty() {
+            return Err(format!("missing the new name for {from:?}"));
+        }
+        let property = match from.trim() {
+            "title" | "name" => &mut map.name,
+            "url" | "google_maps_url" => &mut map.url,
+            "note" => &mut map.note,
+            "comment" => &mut map.comment,
+            other => {
+                return Err(format!(
+                    "unknown property {other:?}, expected title, url, note \
+                    or comment"
+                ))
+            }
+        };
+        *property = to.to_string();
+    }
+    Ok(map)
+}
+
+/// Write the features to the output file.
+pub(crate) fn write_output(
+    path: &Path,
+    features: &FeatureCollection,
+    options: &OutputOptions,
+) -> Result<()> {
+    let features = &options.output_features(features);
+    // without serde_json's preserve_order feature, properties are kept sorted
+    // by key, so the output is the same from run to run. A test checks that
+    // no dependency turns the feature on.
+    let contents = match options.format {
+        OutputFormat::Geojson if options.pretty => {
+            serde_json::to_string_pretty(features)?
+        }
+        OutputFormat::Geojson => features.to_string(),
+        OutputFormat::Gpx => to_gpx(features, options.null_island_epsilon),
+        OutputFormat::Ndjson => {
+            features.features.iter().map(|f| format!("{f}\n")).collect()
+        }
+        OutputFormat::Csv => {
+            to_csv(features, options.null_island_epsilon, &options.columns)?
+        }
+    };
+    match is_stdio(path) {
+        true => io::stdout().write_all(contents.as_bytes())?,
+        // being killed mid-write can't leave a truncated output
+        false if is_gzip(path) => {
+            let mut encoder = GzEncoder::new(vec![], Compression::default());
+            encoder.write_all(contents.as_bytes())?;
+            write_atomically(path, encoder.finish()?)?;
+        }
+        false => write_atomically(path, contents)?,
+    }
+    Ok(())
+}
+
+/// The feature with its point coordinates rounded to the number of decimal
+/// places. Other geometries are left as they are.
+fn rounded_feature(feature: &Feature, precision: u32) -> Feature {
+    let mut feature = feature.clone();
+    if let Some(Geometry {
+        value: Value::Point(ref mut coords),
+        ..
+    }) = feature.geometry
+    {
+        *coords = round_all(coords, precision);
+    }
+    feature
+}
+
+/// Round each number to the number of decimal places, rounding halves away
+/// from zero.
+pub(crate) fn round_all(values: &[f64], precision: u32) -> Vec<f64> {
+    let scale = 10f64.powi(precision.min(15) as i32);
+    values.iter().map(|v| (v * scale).round() / scale).collect()
+}
+
+/// Convert the point features to GPX waypoints. Points at null island are left
+/// out, since they're missing coordinate data.
+fn to_gpx(features: &FeatureCollection, null_island_epsilon: f64) -> String {
+    let mut gpx = String::from(
+        "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n\
+        <gpx version=\"1.1\" creator=\"gmaps-coords\" \
+        xmlns=\"http://www.topografix.com/GPX/1/1\">\n",
+    );
+    for feature in &features.features {
+        let Some(Geometry {
+            value: Value::Point(ref coords),
+            ..
+        }) = feature.geometry
+        else {
+            continue;
+        };
+        let (Some(lng), Some(lat)) = (coords.first(), coords.get(1)) else {
+            continue;
+        };
+        if is_null_island(coords, null_island_epsilon) {
+            continue;
+        }
+        let property = |key: &str| {
+            feature
+                .properties
+                .as_ref()
+                .and_then(|p| p.get(key))
+                .and_then(|v| v.as_str())
+        };
+        gpx.push_str(&format!("  <wpt lat=\"{lat}\" lon=\"{lng}\">\n"));
+        if let Some(alt) = coords.get(2) {
+            gpx.push_str(&format!("    <ele>{alt}</ele>\n"));
+        }
+        if let Some(name) = property("name").or_else(|| property("Title")) {
+            gpx.push_str(&format!("    <name>{}</name>\n", escape(name)));
+        }
+        let desc: Vec<&str> = ["note", "comment"]
+            .into_iter()
+            .filter_map(property)
+            .collect();
+        if !desc.is_empty() {
+            let desc = desc.join("\n");
+            gpx.push_str(&format!("    <desc>{}</desc>\n", escape(&desc)));
+        }
+        gpx.push_str("  </wpt>\n");
+    }
+    gpx.push_str("</gpx>\n");
+    gpx
+}
+
+/// Read the coordinates of every place already found in an output file.
+pub(crate) fn read_resolved_coords(
+    path: &Path,
+    format: OutputFormat,
+    properties: &PropertyMap,
+    null_island_epsilon: f64,
+) -> Result<HashMap<String, LatLng>> {
+    Ok(read_output_features(path, format, properties)?
+        .iter()
+        .filter_map(|feature| match feature.geometry {
+            Some(Geometry {
+                value: Value::Point(ref coords),
+                ..
+            }) if !is_null_island(coords, null_island_epsilon) => {
+                let url = feature
+                    .properties
+                    .as_ref()
+                    .and_then(|p| p.get("google_maps_url"))
+                    .and_then(|v| v.as_str())?;
+                Some((url.to_string(), LatLng::from_geojson_point(coords)?))
+            }
+            _ => None,
+        })
+        .collect())
+}
+
+/// Read the features of an existing output file, if there is one, with their
+/// properties renamed back from the property map.
+pub(crate) fn read_output_features(
+    path: &Path,
+    format: OutputFormat,
+    properties: &PropertyMap,
+) -> Result<Vec<Feature>> {
+    let s = match read_input_to_string(path) {
+        Ok(s) => s,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => String::new(),
+        Err(e) => return Err(e.into()),
+    };
+    if s.trim().is_empty() {
+        // created by an earlier run that didn't find anything
+        return Ok(vec![]);
+    }
+    let mut features: Vec<Feature> = match format {
+        OutputFormat::Ndjson => s
+            .lines()
+            .filter(|line| !line.trim().is_empty())
+            .map(Feature::from_str)
+            .collect::<Result<_, _>>()?,
+        OutputFormat::Csv => csv_features(&s)?,
+        _ => FeatureCollection::from_str(&s)?.features,
+    };
+    if format != OutputFormat::Gpx {
+        features.iter_mut().for_each(|f| properties.restore(f));
+    }
+    Ok(features)
+}
+
+/// The existing features, with those for the same url as a new feature
+/// replaced by it, followed by the rest of the new features.
+pub(crate) fn merged_features(
+    existing: &[Feature],
+    features: &FeatureCollection,
+) -> FeatureCollection {
+    let url = |feature: &Feature| {
+        feature
+            .properties
+            .as_ref()
+            .and_then(|p| p.get("google_maps_url"))
+            .and_then(|v| v.as_str())
+            .map(str::to_string)
+    };
+    let mut new: HashMap<String, usize> = HashMap::new();
+    for (i, feature) in features.features.iter().enumerate() {
+        if let Some(url) = url(feature) {
+            new.entry(url).or_insert(i);
+        }
+    }
+    let mut used = vec![false; features.features.len()];
+    let mut merged: Vec<Feature> = existing
+        .iter()
+        .map(|feature| match url(feature).and_then(|u| new.get(&u)) {
+            Some(&i) if !used[i] => {
+                used[i] = true;
+                features.features[i].clone()
+            }
+            _ => feature.clone(),
+        })
+        .collect();
+    merged.extend(
+        features
+            .features
+            .iter()
+            .zip(used)
+            .filter(|(_, used)| !used)
+            .map(|(feature, _)| feature.clone()),
+    );
+    FeatureCollection {
+        bbox: features.bbox.clone(),
+        features: merged,
+        foreign_members: features.foreign_members.clone(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use geojson::JsonObject;
+
+    use super::*;
+    use crate::geojson::NULL_ISLAND_EPSILON;
+
+    #[test]
+    fn properties_are_written_sorted_by_key() {
+        let mut properties = JsonObject::new();
+        for key in ["zone", "name", "address", "google_maps_url"] {
+            properties.insert(key.into(), key.into());
+        }
+        let features = FeatureCollection {
+            bbox: None,
+            features: vec![Feature {
+                geometry: Some(Value::Point(vec![2.5, 1.5]).into()),
+                properties: Some(properties),
+                ..Default::default()
+            }],
+            foreign_members: None,
+        };
+        let path = std::env::temp_dir()
+            .join(format!("gmaps-coords-{}-sorted.json", std::process::id()));
+        for pretty in [false, true] {
+            let options = OutputOptions {
+                format: OutputFormat::Geojson,
+                null_island_epsilon: NULL_ISLAND_EPSILON,
+                precision: None,
+                pretty,
+                properties: PropertyMap::default(),
+                columns: vec![],
+            };
+            write_output(&path, &features, &options).unwrap();
+            let written = fs::read_to_string(&path).unwrap();
+            let positions: Vec<usize> =
+                ["address", "google_maps_url", "name", "zone"]
+                    .iter()
+                    .map(|key| written.find(&format!("\"{key}\":")).unwrap())
+                    .collect();
+            assert!(positions.is_sorted(), "{written}");
+        }
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,283 @@
+//! The browser tab that places are looked up in.
+
+use std::future::Future;
+
+use fantoccini::{error::CmdError, Client, Locator};
+use tokio::time::Duration;
+use url::Url;
+
+use crate::{GmapsError, WebDriverError};
+
+/// An element on the page, found by a CSS selector or an XPath expression.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Selector<'a> {
+    Css(&'a str),
+    XPath(&'a str),
+}
+
+impl<'a> From<Selector<'a>> for Locator<'a> {
+    fn from(selector: Selector<'a>) -> Self {
+        match selector {
+            Selector::Css(css) => Locator::Css(css),
+            Selector::XPath(xpath) => Locator::XPath(xpath),
+        }
+    }
+}
+
+/// The commands a lookup sends to the browser tab, so it can be driven by
+/// something other than a fantoccini client, e.g. another WebDriver library
+/// or a stand-in page in tests.
+pub trait Page: Sync {
+    /// Navigate to the url.
+    fn goto(
+        &self,
+        url: &str,
+    ) -> impl Future<Output = Result<(), WebDriverError>> + Send;
+
+    /// The url the tab is at now, which changes as the page redirects.
+    fn current_url(
+        &self,
+    ) -> impl Future<Output = Result<Url, WebDriverError>> + Send;
+
+    /// Wait until an element is on the page, polling every interval up to the
+    /// timeout, and return its text.
+    fn wait_for_text(
+        &self,
+        selector: Selector<'_>,
+        every: Duration,
+        at_most: Duration,
+    ) -> impl Future<Output = Result<String, WebDriverError>> + Send;
+
+    /// Whether an element is on the page now.
+    fn has_element(
+        &self,
+        selector: Selector<'_>,
+    ) -> impl Future<Output = bool> + Send;
+
+    /// The attribute of an element on the page, or None if either isn't
+    /// there.
+    fn attr(
+        &self,
+        selector: Selector<'_>,
+        name: &str,
+    ) -> impl Future<Output = Result<Option<String>, WebDriverError>> + Send;
+
+    /// Click an element on the page, returning false if it isn't there.
+    fn click(
+        &self,
+        selector: Selector<'_>,
+    ) -> impl Future<Output = Result<bool, WebDriverError>> + Send;
+}
+
+impl From<CmdError> for WebDriverError {
+    fn from(e: CmdError) -> Self {
+        let crashed =
+            matches!(&e, CmdError::Standard(w) if w.message.contains("crash"));
+        let kind = if e.is_session_not_created() {
+            WebDriverError::SessionNotCreated
+        } else if e.is_timeout() || e.is_script_timeout() {
+            WebDriverError::NavigationTimeout
+        } else if crashed {
+            WebDriverError::RendererCrashed
+        } else if e.is_invalid_session_id() || matches!(e, CmdError::Lost(_)) {
+            WebDriverError::SessionLost
+        } else {
+            WebDriverError::Other
+        };
+        kind(Box::new(e))
+    }
+}
+
+impl From<CmdError> for GmapsError {
+    fn from(e: CmdError) -> Self {
+        GmapsError::WebDriver(e.into())
+    }
+}
+
+impl Page for Client {
+    async fn goto(&self, url: &str) -> Result<(), WebDriverError> {
+        Ok(Client::goto(self, url).await?)
+    }
+
+    async fn current_url(&self) -> Result<Url, WebDriverError> {
+        Ok(Client::current_url(self).await?)
+    }
+
+    async fn wait_for_text(
+        &self,
+        selector: Selector<'_>,
+        every: Duration,
+        at_most: Duration,
+    ) -> Result<String, WebDriverError> {
+        let element = self
+            .wait()
+            .every(every)
+            .at_most(at_most)
+            .for_element(selector.into())
+            .await?;
+        Ok(element.text().await?)
+    }
+
+    async fn has_element(&self, selector: Selector<'_>) -> bool {
+        self.find(selector.into()).await.is_ok()
+    }
+
+    async fn attr(
+        &self,
+        selector: Selector<'_>,
+        name: &str,
+    ) -> Result<Option<String>, WebDriverError> {
+        match self.find(selector.into()).await {
+            Ok(element) => Ok(element.attr(name).await?),
+            Err(_) => Ok(None),
+        }
+    }
+
+    async fn click(
+        &self,
+        selector: Selector<'_>,
+    ) -> Result<bool, WebDriverError> {
+        match self.find(selector.into()).await {
+            Ok(element) => {
+                element.click().await?;
+                Ok(true)
+            }
+            Err(_) => Ok(false),
+        }
+    }
+}
+
+/// A stand-in browser tab for tests.
+#[cfg(test)]
+pub(crate) mod fake {
+    use std::{
+        collections::{HashMap, VecDeque},
+        sync::Mutex,
+    };
+
+    use tokio::time::{sleep, Duration};
+    use url::Url;
+
+    use super::{Page, Selector};
+    use crate::WebDriverError;
+
+    /// A stand-in browser tab, at each of a url's scripted redirects in turn
+    /// as it's polled, with a fixed set of elements on every page.
+    #[derive(Default)]
+    pub(crate) struct FakePage {
+        redirects: HashMap<String, Vec<String>>,
+        /// The elements by selector, with the text or attribute they have.
+        elements: HashMap<String, String>,
+        /// The url the tab is at, then the redirects still to come.
+        tab: Mutex<(String, VecDeque<String>)>,
+        /// How long going to each url takes.
+        delays: HashMap<String, Duration>,
+        /// The urls gone to, in order.
+        pub(crate) visited: Mutex<Vec<String>>,
+    }
+
+    impl FakePage {
+        pub(crate) fn redirect(mut self, url: &str, to: &[&str]) -> Self {
+            let to = to.iter().map(|to| to.to_string()).collect();
+            self.redirects.insert(url.to_string(), to);
+            self
+        }
+
+        pub(crate) fn element(mut self, selector: &str, value: &str) -> Self {
+            self.elements
+                .insert(selector.to_string(), value.to_string());
+            self
+        }
+
+        pub(crate) fn delay(mut self, url: &str, delay: Duration) -> Self {
+            self.delays.insert(url.to_string(), delay);
+            self
+        }
+
+        fn find(&self, selector: Selector<'_>) -> Option<String> {
+            let (Selector::Css(s) | Selector::XPath(s)) = selector;
+            self.elements.get(s).cloned()
+        }
+    }
+
+    impl Page for FakePage {
+        async fn goto(&self, url: &str) -> Result<(), WebDriverError> {
+            self.visited.lock().unwrap().push(url.to_string());
+            if let Some(delay) = self.delays.get(url) {
+                sleep(*delay).await;
+            }
+            let redirects = self.redirects.get(url).cloned();
+            *self.tab.lock().unwrap() =
+                (url.to_string(), redirects.unwrap_or_default().into());
+            Ok(())
+        }
+
+        async fn current_url(&self) -> Result<Url, WebDriverError> {
+            let mut tab = self.tab.lock().unwrap();
+            if let Some(next) = tab.1.pop_front() {
+                tab.0 = next;
+            }
+            Url::parse(&tab.0).map_err(|e| WebDriverError::Other(e.into()))
+        }
+
+        async fn wait_for_text(
+            &self,
+            selector: Selector<'_>,
+            _every: Duration,
+            _at_most: Duration,
+        ) -> Result<String, WebDriverError> {
+            self.find(selector).ok_or_else(|| {
+                WebDriverError::NavigationTimeout("No such element".into())
+            })
+        }
+
+        async fn has_element(&self, selector: Selector<'_>) -> bool {
+            self.find(selector).is_some()
+        }
+
+        async fn attr(
+            &self,
+            selector: Selector<'_>,
+            _name: &str,
+        ) -> Result<Option<String>, WebDriverError> {
+            Ok(self.find(selector))
+        }
+
+        async fn click(
+            &self,
+            selector: Selector<'_>,
+        ) -> Result<bool, WebDriverError> {
+            Ok(self.find(selector).is_some())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn selectors_into_locators() {
+        assert!(matches!(
+            Locator::from(Selector::Css("div[role='feed']")),
+            Locator::Css("div[role='feed']")
+        ));
+        assert!(matches!(
+            Locator::from(Selector::XPath("//button")),
+            Locator::XPath("//button")
+        ));
+    }
+
+    #[test]
+    fn fantoccini_errors_are_classified() {
+        let lost = CmdError::Lost(std::io::Error::other("connection reset"));
+        assert!(matches!(
+            WebDriverError::from(lost),
+            WebDriverError::SessionLost(_)
+        ));
+        assert!(matches!(
+            WebDriverError::from(CmdError::WaitTimeout),
+            WebDriverError::Other(_)
+        ));
+    }
+}